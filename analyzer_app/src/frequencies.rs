@@ -1,7 +1,9 @@
 use iced::{Element, Length};
 use plotters_iced::{Chart, ChartBuilder, ChartWidget, DrawingBackend};
 
-use audio::{FFTResult, Message};
+use audio::{FFTResult, PSDResult};
+
+use crate::Message;
 
 pub struct FrequenciesChart {
     latest_ffts: Option<FFTResult>,
@@ -12,7 +14,7 @@ impl FrequenciesChart {
         FrequenciesChart { latest_ffts: None }
     }
 
-    pub fn view(&self) -> Element<Message> {
+    pub fn view(&self) -> Element<'_, Message> {
         ChartWidget::new(self)
             .width(Length::Fill)
             .height(Length::Fill)
@@ -35,3 +37,37 @@ impl Chart<Message> for FrequenciesChart {
         }
     }
 }
+
+/// Long-term average spectra, to show next to the `FrequenciesChart`
+pub struct PSDChart {
+    latest_psds: Option<PSDResult>,
+}
+
+impl PSDChart {
+    pub fn new() -> PSDChart {
+        PSDChart { latest_psds: None }
+    }
+
+    pub fn view(&self) -> Element<'_, Message> {
+        ChartWidget::new(self)
+            .width(Length::Fill)
+            .height(Length::Fill)
+            .into()
+    }
+
+    pub fn update(&mut self, message: PSDResult) {
+        self.latest_psds = Some(message);
+    }
+}
+
+impl Chart<Message> for PSDChart {
+    type State = ();
+
+    fn build_chart<DB: DrawingBackend>(&self, _state: &Self::State, builder: ChartBuilder<DB>) {
+        if let Some(latest) = self.latest_psds.as_ref() {
+            // TODO: display more than the first channel
+            charts::build_psd_chart(builder, latest.psds.first().unwrap())
+                .expect("Failed to build chart");
+        }
+    }
+}
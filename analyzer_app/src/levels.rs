@@ -7,7 +7,9 @@ use plotters_iced::{Chart, ChartBuilder, ChartWidget, DrawingBackend};
 
 use audio::dsp::Decibels;
 use audio::stream;
-use audio::{Message, RMSLevels};
+use audio::RMSLevels;
+
+use crate::Message;
 
 pub struct LevelsChart {
     /// The width of the chart
@@ -77,7 +79,7 @@ impl LevelsChart {
         }
     }
 
-    pub fn view(&self) -> Element<Message> {
+    pub fn view(&self) -> Element<'_, Message> {
         ChartWidget::new(self)
             .width(Length::Fill)
             .height(Length::Fill)
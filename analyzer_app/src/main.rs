@@ -1,6 +1,8 @@
+use std::path::PathBuf;
+use std::process::exit;
 use std::thread::JoinHandle;
 
-use async_channel::Receiver;
+use async_channel::Sender;
 use clap::Parser;
 use futures::sink::SinkExt;
 use iced::{widget, Element, Length, Padding, Subscription};
@@ -9,11 +11,16 @@ mod frequencies;
 mod levels;
 mod mandelbrot;
 
-use audio::stream::executor::{Executor, CHANNEL_MAX};
+use audio::dsp::fft::Window;
+use audio::stream::delivery::{DeliveryStats, ResultReceiver};
+use audio::stream::device::{self, DeviceId, DeviceSelector};
+use audio::stream::executor::{
+    AnalysisConfig, AnalysisConfigError, Command, Control, Executor, ExecutorResult, RecordTo,
+    StopReason,
+};
 use audio::stream::input::{ChannelCount, SampleRate};
-use audio::stream::Instant;
-use audio::Message;
-use frequencies::FrequenciesChart;
+use audio::stream::{Instant, Period};
+use frequencies::{FrequenciesChart, PSDChart};
 
 #[derive(Debug, Parser)]
 struct Args {
@@ -23,6 +30,82 @@ struct Args {
     /// The sample rate (Hz) for audio input
     #[arg(short, long, default_value_t = 44100)]
     sample_rate: u32,
+    /// Record the audio input to this file (overwriting it, if it exists)
+    #[arg(long, conflicts_with_all = ["record_dir", "no_record"])]
+    record: Option<PathBuf>,
+    /// Record the audio input to a new, timestamped file in this directory
+    /// (which is also where recordings started from the UI go)
+    #[arg(long, default_value = ".", conflicts_with = "no_record")]
+    record_dir: PathBuf,
+    /// Don't record the audio input
+    #[arg(long)]
+    no_record: bool,
+    /// The audio host (API) to use, instead of the platform default
+    #[arg(long)]
+    host: Option<String>,
+    /// The input device to use (by name or index), instead of the default
+    #[arg(long)]
+    input_device: Option<DeviceId>,
+    /// List the available audio hosts and devices, and exit
+    #[arg(long)]
+    list_devices: bool,
+    /// The number of samples in each FFT
+    #[arg(long, default_value_t = 8192)]
+    fft_width: usize,
+    /// The number of samples between the starts of subsequent FFTs (the FFT
+    /// width, if not given)
+    #[arg(long)]
+    fft_hop: Option<usize>,
+    /// Zero-pad each FFT period to this many samples, for finer frequency
+    /// bins (no padding, if not given)
+    #[arg(long)]
+    fft_padded_width: Option<usize>,
+    /// The window function to apply before each FFT (rectangular, hann,
+    /// hamming, blackman-harris, flat-top, or kaiser[:<beta>])
+    #[arg(long, default_value = "rectangular")]
+    window: Window,
+    /// Don't compute RMS levels
+    #[arg(long)]
+    no_rms: bool,
+    /// The number of FFT periods to average for the long-term power spectral
+    /// density (0 to not compute it)
+    #[arg(long, default_value_t = 16)]
+    psd_periods: usize,
+    /// The window function to apply to the periods of the power spectral
+    /// density (as for --window)
+    #[arg(long, default_value = "hann")]
+    psd_window: Window,
+}
+
+impl Args {
+    fn input_device(&self) -> DeviceSelector {
+        DeviceSelector {
+            host: self.host.clone(),
+            device: self.input_device.clone(),
+        }
+    }
+
+    fn analysis(&self) -> AnalysisConfig {
+        AnalysisConfig {
+            fft_width: self.fft_width,
+            hop: self.fft_hop.unwrap_or(self.fft_width),
+            padded_width: self.fft_padded_width,
+            window: self.window,
+            rms: !self.no_rms,
+            psd_periods: self.psd_periods,
+            psd_window: self.psd_window,
+        }
+    }
+
+    fn recording(&self) -> Option<RecordTo> {
+        if self.no_record {
+            None
+        } else if let Some(path) = &self.record {
+            Some(RecordTo::File(path.clone()))
+        } else {
+            Some(RecordTo::Timestamped(self.record_dir.clone()))
+        }
+    }
 }
 
 impl Default for Args {
@@ -30,16 +113,50 @@ impl Default for Args {
         Args {
             channels: 2,
             sample_rate: 44100,
+            record: None,
+            record_dir: PathBuf::from("."),
+            no_record: false,
+            host: None,
+            input_device: None,
+            list_devices: false,
+            fft_width: 8192,
+            fft_hop: None,
+            fft_padded_width: None,
+            window: Window::Rectangular,
+            no_rms: false,
+            psd_periods: 16,
+            psd_window: Window::Hann,
         }
     }
 }
 
+#[derive(Debug, Clone)]
+enum Message {
+    /// (from the executor)
+    Audio(audio::Message),
+    /// Start a new recording, or stop the one in progress
+    ToggleRecording,
+}
+
 struct Analyzer {
     time: Instant,
     rms_levels: Vec<f32>,
-    _audio_thread: JoinHandle<()>,
-    audio_messages: Receiver<Message>,
+    /// The most recent period that was missing from the input, and the total
+    /// number of missing samples
+    last_dropout: Option<Period>,
+    dropped_samples: usize,
+    /// (None once it has stopped)
+    audio_thread: Option<JoinHandle<ExecutorResult>>,
+    /// Why the audio input stopped, once it has
+    stopped: Option<String>,
+    audio_commands: Sender<Control<Command>>,
+    recording: bool,
+    /// Where to start recordings from the UI
+    record_dir: PathBuf,
+    audio_messages: ResultReceiver,
     frequencies: FrequenciesChart,
+    /// (None if PSDs aren't being computed)
+    psd: Option<PSDChart>,
 }
 
 #[derive(Hash)]
@@ -48,25 +165,60 @@ enum SubscriptionId {
 }
 
 impl Analyzer {
-    fn new(args: Args) -> Analyzer {
-        let (sender, audio_messages) = async_channel::bounded(CHANNEL_MAX);
+    fn new(args: Args) -> Result<Analyzer, AnalysisConfigError> {
+        let psd = (args.psd_periods > 0).then(PSDChart::new);
+        let recording = args.recording();
+        let record_dir = args.record_dir.clone();
         let executor = Executor::new(
-            sender,
             ChannelCount::new(args.channels),
             SampleRate::new(args.sample_rate),
-        );
+        )
+        .with_input_device(args.input_device())
+        .with_recording(recording.clone())
+        .with_analysis(args.analysis())?;
+        let (audio_commands, audio_messages, audio_thread) = executor.start();
 
-        Analyzer {
+        Ok(Analyzer {
             time: Instant::new(0, SampleRate::new(1)),
             rms_levels: Vec::new(),
-            _audio_thread: executor.start(),
+            last_dropout: None,
+            dropped_samples: 0,
+            audio_thread: Some(audio_thread),
+            stopped: None,
+            audio_commands,
+            recording: recording.is_some(),
+            record_dir,
             audio_messages,
             frequencies: FrequenciesChart::new(),
-        }
+            psd,
+        })
     }
 }
 
 fn update(state: &mut Analyzer, message: Message) {
+    match message {
+        Message::Audio(m) => receive(state, m),
+        Message::ToggleRecording => {
+            let command = if state.recording {
+                Command::StopRecording
+            } else {
+                Command::StartRecording(RecordTo::Timestamped(state.record_dir.clone()))
+            };
+            // (which only fails once the executor has stopped)
+            if state
+                .audio_commands
+                .try_send(Control::Command(command))
+                .is_ok()
+            {
+                state.recording = !state.recording;
+            }
+        }
+    }
+}
+
+/// Update the displayed results from the executor
+fn receive(state: &mut Analyzer, message: audio::Message) {
+    use audio::Message;
     match message {
         Message::RMSLevels(l) => {
             state.rms_levels = l.values.clone();
@@ -76,14 +228,69 @@ fn update(state: &mut Analyzer, message: Message) {
             state.time = f.end_time;
             state.frequencies.update(f);
         }
-        Message::AudioStreamClosed => todo!(),
+        Message::PSDResult(p) => {
+            if let Some(psd) = &mut state.psd {
+                psd.update(p);
+            }
+        }
+        Message::Dropout(p) => {
+            state.dropped_samples += p.duration().sample_count();
+            state.last_dropout = Some(p);
+        }
+        Message::AnalysisConfigRejected(e) => {
+            // (the analyzer doesn't reconfigure its analyses while it runs, so
+            // this isn't expected)
+            println!("Analysis configuration rejected: {:?}", e);
+        }
+        Message::AudioStreamClosed => {
+            // (the results are closed when the executor's thread finishes,
+            // so this doesn't wait for long)
+            let result = state.audio_thread.take().map(JoinHandle::join);
+            state.stopped = Some(match result {
+                Some(Ok(Ok(StopReason::EndOfStream))) => "the input ended".to_string(),
+                Some(Ok(Ok(StopReason::Stop))) => "it was stopped".to_string(),
+                Some(Ok(Ok(StopReason::Disconnected))) => "it was disconnected".to_string(),
+                Some(Ok(Err(e))) => format!("{:?}", e),
+                Some(Err(_)) => "the audio thread panicked".to_string(),
+                None => "unknown".to_string(),
+            });
+        }
     };
 }
 
-fn view(state: &Analyzer) -> Element<Message> {
+fn view(state: &Analyzer) -> Element<'_, Message> {
     // Wrap the UI in a Container that can be configured to fill whatever
     // the current window size is, and lay out children to use that space
-    widget::Container::new(widget::column![state.frequencies.view(),])
+    let mut content = widget::column![state.frequencies.view()];
+    if let Some(psd) = &state.psd {
+        content = content.push(psd.view());
+    }
+    if let Some(dropout) = state.last_dropout {
+        content = content.push(widget::text(format!(
+            "Input dropouts: {} samples missing, most recently at {:.1}s",
+            state.dropped_samples,
+            dropout.start().as_secs_from_start_f32()
+        )));
+    }
+    content = content.push(
+        widget::button(if state.recording {
+            "Stop recording"
+        } else {
+            "Record"
+        })
+        .on_press_maybe(state.stopped.is_none().then_some(Message::ToggleRecording)),
+    );
+    if let Some(reason) = &state.stopped {
+        content = content.push(widget::text(format!("Audio input stopped: {}", reason)));
+    }
+    let stats = state.audio_messages.stats();
+    if stats != DeliveryStats::default() {
+        content = content.push(widget::text(format!(
+            "Display falling behind: {} spectra and {} level updates skipped",
+            stats.skipped_ffts, stats.dropped
+        )));
+    }
+    widget::Container::new(content)
         .width(Length::Fill)
         .height(Length::Fill)
         .padding(Padding::new(5.))
@@ -91,6 +298,10 @@ fn view(state: &Analyzer) -> Element<Message> {
 }
 
 fn subscription(state: &Analyzer) -> Subscription<Message> {
+    if state.stopped.is_some() {
+        // (keeping the last results displayed)
+        return Subscription::none();
+    }
     let audio_messages = state.audio_messages.clone();
     Subscription::run_with_id(
         SubscriptionId::AudioInput,
@@ -99,9 +310,12 @@ fn subscription(state: &Analyzer) -> Subscription<Message> {
             |mut output| async move {
                 loop {
                     match audio_messages.recv().await {
-                        Ok(m) => output.send(m).await.unwrap(),
-                        Err(_) => {
-                            output.send(Message::AudioStreamClosed).await.unwrap();
+                        Some(m) => output.send(Message::Audio(m)).await.unwrap(),
+                        None => {
+                            output
+                                .send(Message::Audio(audio::Message::AudioStreamClosed))
+                                .await
+                                .unwrap();
                             return;
                         }
                     }
@@ -112,6 +326,21 @@ fn subscription(state: &Analyzer) -> Subscription<Message> {
 }
 
 fn main() -> iced::Result {
+    let args = Args::parse();
+    if args.list_devices {
+        for host in device::hosts() {
+            print!("{}", host);
+        }
+        return Ok(());
+    }
+    let analyzer = match Analyzer::new(args) {
+        Ok(analyzer) => analyzer,
+        Err(e) => {
+            println!("Invalid analysis options: {:?}", e);
+            exit(2);
+        }
+    };
+
     iced::application("Formant Analyzer", update, view)
         // This is an unreliable work-around for a bug with nvidia's linux
         // vulkan drivers, apparently, see
@@ -119,5 +348,5 @@ fn main() -> iced::Result {
         // If it doesn't work, try setting environment (source env.sh)
         .antialiasing(true)
         .subscription(subscription)
-        .run_with(|| (Analyzer::new(Args::parse()), iced::Task::none()))
+        .run_with(move || (analyzer, iced::Task::none()))
 }
@@ -76,7 +76,7 @@ impl Chart<Message> for MandelbrotChart {
 
 impl MandelbrotChart {
     #[allow(dead_code)]
-    pub fn view(&self) -> Element<Message> {
+    pub fn view(&self) -> Element<'_, Message> {
         ChartWidget::new(self)
             .width(Length::Fixed(400.0))
             .height(Length::Fixed(400.0))
@@ -95,7 +95,8 @@ mod tests {
 
     fn draw_bitmap() -> Result<(), Box<dyn std::error::Error>> {
         if let Some(parent) = Path::new(OUT_FILE_NAME).parent() {
-            fs::create_dir_all(parent).expect(&format!("Failed to create dir: {:?}", parent));
+            fs::create_dir_all(parent)
+                .unwrap_or_else(|_| panic!("Failed to create dir: {:?}", parent));
         }
         let root = BitMapBackend::new(OUT_FILE_NAME, (800, 600)).into_drawing_area();
         root.fill(&WHITE)?;
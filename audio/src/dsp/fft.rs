@@ -1,6 +1,8 @@
 use std::f32::consts::PI;
-use std::iter::zip;
-use std::sync::Arc;
+use std::f64::consts::TAU;
+use std::iter::{self, zip};
+use std::str::FromStr;
+use std::sync::{Arc, LazyLock, Mutex};
 
 use approx::AbsDiffEq;
 use num_complex::Complex;
@@ -10,22 +12,261 @@ use crate::stream::buffer::ChannelPeriod;
 use crate::stream::input::SampleRate;
 use crate::Hz;
 
+/// A window function, which each period is multiplied by before it is
+/// transformed, trading off frequency resolution (the width of the main lobe
+/// of each peak) against spectral leakage (the level of its side lobes)
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Window {
+    /// i.e. no window
+    #[default]
+    Rectangular,
+    Hann,
+    Hamming,
+    /// The 4-term Blackman-Harris window, which has very low side lobes
+    BlackmanHarris,
+    /// Has a very flat main lobe, so that the amplitudes of peaks are accurate
+    /// even between bins, at the cost of resolution
+    FlatTop,
+    /// With the given beta, which increases the main lobe width and lowers
+    /// the side lobes (0 is rectangular, ~8.6 is similar to Blackman-Harris)
+    Kaiser(f32),
+}
+
+impl Window {
+    /// The beta of a Kaiser window that is parsed without one
+    pub const DEFAULT_KAISER_BETA: f32 = 8.6;
+
+    /// The (periodic, i.e. DFT-even) window coefficients for a period of the
+    /// given length
+    pub fn coefficients(&self, len: usize) -> Vec<f32> {
+        let n = len as f64;
+        // (the sum of cosine terms with the given coefficients)
+        let cosines = |i: f64, a: &[f64]| -> f64 {
+            a.iter()
+                .enumerate()
+                .map(|(k, a)| a * (TAU * (k as f64) * i / n).cos())
+                .sum()
+        };
+        (0..len)
+            .map(|i| {
+                let i = i as f64;
+                let w = match *self {
+                    Window::Rectangular => 1.,
+                    Window::Hann => cosines(i, &[0.5, -0.5]),
+                    Window::Hamming => cosines(i, &[0.54, -0.46]),
+                    Window::BlackmanHarris => cosines(i, &[0.35875, -0.48829, 0.14128, -0.01168]),
+                    Window::FlatTop => cosines(
+                        i,
+                        &[
+                            0.21557895,
+                            -0.41663158,
+                            0.277263158,
+                            -0.083578947,
+                            0.006947368,
+                        ],
+                    ),
+                    Window::Kaiser(beta) => {
+                        let beta = f64::from(beta);
+                        let x = 2. * i / n - 1.;
+                        bessel_i0(beta * (1. - x * x).sqrt()) / bessel_i0(beta)
+                    }
+                };
+                w as f32
+            })
+            .collect()
+    }
+
+    /// The mean of the coefficients for a period of the given length, i.e. the
+    /// factor by which the window scales the amplitude of a sinusoid (at the
+    /// center of a bin)
+    pub fn coherent_gain(&self, len: usize) -> f32 {
+        self.coefficients(len).iter().sum::<f32>() / len as f32
+    }
+}
+
+/// The modified Bessel function of the first kind, of order 0, by its power
+/// series (which converges quickly for the arguments of a Kaiser window)
+fn bessel_i0(x: f64) -> f64 {
+    let mut sum = 1.;
+    let mut term = 1.;
+    let mut k = 1.;
+    while term > sum * 1e-12 {
+        term *= (x / (2. * k)).powi(2);
+        sum += term;
+        k += 1.;
+    }
+    sum
+}
+
+impl FromStr for Window {
+    type Err = String;
+
+    /// The name of the window, e.g. "hann", or "kaiser:<beta>" (where beta is
+    /// finite and not negative)
+    fn from_str(s: &str) -> Result<Window, String> {
+        let s = s.to_lowercase();
+        match s.split_once(':') {
+            Some(("kaiser", beta)) => beta
+                .parse()
+                .ok()
+                .filter(|b: &f32| b.is_finite() && *b >= 0.)
+                .map(Window::Kaiser)
+                .ok_or_else(|| format!("invalid Kaiser window beta: {}", beta)),
+            Some(_) => Err(format!("unknown window: {}", s)),
+            None => match s.as_str() {
+                "rectangular" => Ok(Window::Rectangular),
+                "hann" => Ok(Window::Hann),
+                "hamming" => Ok(Window::Hamming),
+                "blackman-harris" => Ok(Window::BlackmanHarris),
+                "flat-top" => Ok(Window::FlatTop),
+                "kaiser" => Ok(Window::Kaiser(Window::DEFAULT_KAISER_BETA)),
+                _ => Err(format!("unknown window: {}", s)),
+            },
+        }
+    }
+}
+
+/// FFT plans (and their twiddle factors) are shared between everything that
+/// does FFTs of the same length
+static PLANNER: LazyLock<Mutex<FftPlanner<f32>>> = LazyLock::new(|| Mutex::new(FftPlanner::new()));
+
+fn plan_fft_forward(len: usize) -> Arc<dyn Fft<f32>> {
+    PLANNER.lock().unwrap().plan_fft_forward(len)
+}
+
+pub(crate) fn plan_fft_inverse(len: usize) -> Arc<dyn Fft<f32>> {
+    PLANNER.lock().unwrap().plan_fft_inverse(len)
+}
+
 pub struct FFTSequence {
+    period_len: usize,
+    /// (of the padded length)
     fft: Arc<dyn Fft<f32>>,
+    /// For even lengths, a half-length FFT that real samples are packed into
+    /// in pairs (as the real and imaginary parts of each value), otherwise
+    /// the same as `fft`
+    packed_fft: Arc<dyn Fft<f32>>,
+    /// For unpacking the half-length FFT, e ^ (-2 * PI * i * k / N) for k in
+    /// 0..=N/2
+    twiddles: Vec<Complex<f32>>,
+    /// The window coefficients, or None for a rectangular window
+    window: Option<Vec<f32>>,
+    /// Of the window and the zero padding (which is effectively part of the
+    /// window)
+    coherent_gain: f32,
+    /// Reused by each `folded_fft`, to avoid allocating
+    buffer: Vec<Complex<f32>>,
+    scratch: Vec<Complex<f32>>,
 }
 
 impl FFTSequence {
     pub fn new(period_len: usize) -> FFTSequence {
+        FFTSequence::padded(period_len, period_len)
+    }
+
+    /// For FFTs of `fft_len` (which must be at least `period_len`), with each
+    /// period zero-padded up to that length. This gives more (interpolated)
+    /// frequency bins than the period alone would, without needing a longer
+    /// period.
+    pub fn padded(period_len: usize, fft_len: usize) -> FFTSequence {
+        assert!(period_len > 0, "FFT periods must have at least one sample");
+        assert!(fft_len >= period_len);
+        let packed = fft_len.is_multiple_of(2);
+        let packed_fft = plan_fft_forward(if packed { fft_len / 2 } else { fft_len });
+        let twiddles = if packed {
+            (0..=fft_len / 2)
+                .map(|k| Complex::from_polar(1., -2. * PI * k as f32 / fft_len as f32))
+                .collect()
+        } else {
+            Vec::new()
+        };
         FFTSequence {
-            // nb: reusing the planner is recommended if a lot of these are
-            // going to get constructed.
-            fft: FftPlanner::new().plan_fft_forward(period_len),
+            period_len,
+            fft: plan_fft_forward(fft_len),
+            buffer: Vec::with_capacity(packed_fft.len()),
+            scratch: vec![Complex::default(); packed_fft.get_inplace_scratch_len()],
+            packed_fft,
+            twiddles,
+            window: None,
+            coherent_gain: period_len as f32 / fft_len as f32,
+        }
+    }
+
+    pub fn with_window(mut self, window: Window) -> FFTSequence {
+        let len = self.period_len;
+        self.window = match window {
+            Window::Rectangular => None,
+            w => Some(w.coefficients(len)),
+        };
+        self.coherent_gain = window.coherent_gain(len) * len as f32 / self.fft.len() as f32;
+        self
+    }
+
+    /// The coherent gain of the window (see `Window::coherent_gain`),
+    /// including any zero padding, which `PolarFFT::into_folded_windowed`
+    /// corrects for
+    pub fn coherent_gain(&self) -> f32 {
+        self.coherent_gain
+    }
+
+    /// The FFT of a period, already folded (and normalized for the window),
+    /// i.e. the same as `fft(period).into_polar().into_folded_windowed(..)`,
+    /// but taking advantage of the signal being real, which roughly halves
+    /// the work, and without allocating anything but the result
+    pub fn folded_fft(&mut self, period: &ChannelPeriod) -> FoldedFFT {
+        let n = self.fft.len();
+        assert_eq!(period.len(), self.period_len);
+        let packed = !self.twiddles.is_empty();
+        let padding = iter::repeat_n(0., n - self.period_len);
+        match &self.window {
+            Some(window) => fill_buffer(
+                &mut self.buffer,
+                zip(period.iter(), window)
+                    .map(|(y, w)| y * w)
+                    .chain(padding),
+                packed,
+            ),
+            None => fill_buffer(
+                &mut self.buffer,
+                period.iter().copied().chain(padding),
+                packed,
+            ),
+        }
+        self.packed_fft
+            .process_with_scratch(&mut self.buffer, &mut self.scratch);
+
+        let mut values = Vec::with_capacity(n / 2 + 1);
+        if packed {
+            // The FFTs of the even and odd samples are the conjugate-symmetric
+            // and antisymmetric parts of the packed FFT, and combine like a
+            // step of a radix-2 FFT
+            let m = n / 2;
+            for (k, twiddle) in self.twiddles.iter().enumerate() {
+                let z = self.buffer[k % m];
+                let z_conj = self.buffer[(m - k) % m].conj();
+                let even = (z + z_conj) * 0.5;
+                let odd = (z - z_conj) * Complex::new(0., -0.5);
+                values.push((even + twiddle * odd).to_polar());
+            }
+        } else {
+            values.extend(self.buffer[..n / 2 + 1].iter().map(|y| y.to_polar()));
+        }
+        normalize_folded(&mut values, n, self.coherent_gain);
+        FoldedFFT {
+            values,
+            sample_rate: period.sample_rate(),
+            unfolded_length: n,
         }
     }
 
     pub fn fft(&self, period: &ChannelPeriod) -> CartesianFFT {
-        let mut values: Vec<Complex<f32>> =
-            period.iter().map(|y| Complex { re: *y, im: 0. }).collect();
+        let mut values: Vec<Complex<f32>> = match &self.window {
+            Some(window) => zip(period.iter(), window)
+                .map(|(y, w)| Complex { re: y * w, im: 0. })
+                .collect(),
+            None => period.iter().map(|y| Complex { re: *y, im: 0. }).collect(),
+        };
+        values.resize(self.fft.len(), Complex::default());
         self.fft.process(&mut values);
         CartesianFFT {
             values,
@@ -34,6 +275,23 @@ impl FFTSequence {
     }
 }
 
+/// Replace the contents of the buffer with the samples, either packed in
+/// pairs or one per value
+fn fill_buffer(
+    buffer: &mut Vec<Complex<f32>>,
+    mut samples: impl Iterator<Item = f32>,
+    packed: bool,
+) {
+    buffer.clear();
+    if packed {
+        while let (Some(re), Some(im)) = (samples.next(), samples.next()) {
+            buffer.push(Complex { re, im });
+        }
+    } else {
+        buffer.extend(samples.map(|re| Complex { re, im: 0. }));
+    }
+}
+
 /// The result of a FFT, in cartesian form (re + im * i)
 #[derive(Clone, Debug, PartialEq)]
 pub struct CartesianFFT {
@@ -55,14 +313,20 @@ impl CartesianFFT {
     pub fn from_real_signal(signal: Vec<f32>, sample_rate: SampleRate) -> CartesianFFT {
         let mut values: Vec<Complex<f32>> =
             signal.into_iter().map(|y| Complex::new(y, 0.)).collect();
-        FftPlanner::new()
-            .plan_fft_forward(values.len())
-            .process(&mut values);
+        plan_fft_forward(values.len()).process(&mut values);
         CartesianFFT {
             values,
             sample_rate,
         }
     }
+
+    /// The inverse FFT, assuming that the signal was real (i.e. that this is
+    /// conjugate symmetric). Inefficient in the same way as `from_real_signal`.
+    pub fn into_signal(mut self) -> Vec<f32> {
+        let n = self.values.len();
+        plan_fft_inverse(n).process(&mut self.values);
+        self.values.into_iter().map(|y| y.re / n as f32).collect()
+    }
 }
 
 /// The result of a FFT in polar form (r * e ^ (i * Θ))
@@ -97,6 +361,13 @@ impl PolarFFT {
     }
 
     pub fn into_folded(self) -> FoldedFFT {
+        self.into_folded_windowed(1.)
+    }
+
+    /// Like `into_folded`, for the FFT of a windowed signal: magnitudes are
+    /// also divided by the window's coherent gain, so that they are still
+    /// the amplitudes of the signal's components
+    pub fn into_folded_windowed(self, coherent_gain: f32) -> FoldedFFT {
         let n = self.values.len();
         let mut res = FoldedFFT {
             values: self.values,
@@ -106,25 +377,31 @@ impl PolarFFT {
 
         // Delete all negative frequency conjugates :3
         res.values.truncate(n / 2 + 1);
+        normalize_folded(&mut res.values, n, coherent_gain);
+        res
+    }
+}
 
-        // Apply the 1/N normalization factor from the inverse FFT to
-        // magnitudes, making them interpretable as the physical amplitude of
-        // that frequency component of teh signal. Multiply values that have
-        // a conjugate by 2 to account for the removal of its magnitude.
-        let folded_len = res.values.len(); // for the borrow checker
-        for (i, y) in res.values.iter_mut().enumerate() {
-            if i == 0 {
-                // DC never has a conjugate
-                y.0 /= n as f32;
-            } else if (i == folded_len - 1) && (n % 2 == 0) {
-                // If width is odd, the highest positive frequency has no
-                // conjugate
-                y.0 /= n as f32;
-            } else {
-                y.0 *= 2. / n as f32;
-            }
+/// Normalize the magnitudes of the positive frequency half of a FFT of length
+/// n, of a signal that was windowed with the given coherent gain
+fn normalize_folded(values: &mut [(f32, f32)], n: usize, coherent_gain: f32) {
+    let scale = 1. / (n as f32 * coherent_gain);
+    // Apply the 1/N normalization factor from the inverse FFT to
+    // magnitudes, making them interpretable as the physical amplitude of
+    // that frequency component of teh signal. Multiply values that have
+    // a conjugate by 2 to account for the removal of its magnitude.
+    let folded_len = values.len();
+    for (i, y) in values.iter_mut().enumerate() {
+        if i == 0 {
+            // DC never has a conjugate
+            y.0 *= scale;
+        } else if (i == folded_len - 1) && n.is_multiple_of(2) {
+            // If width is odd, the highest positive frequency has no
+            // conjugate
+            y.0 *= scale;
+        } else {
+            y.0 *= 2. * scale;
         }
-        res
     }
 }
 
@@ -178,6 +455,44 @@ impl FoldedFFT {
     pub fn nyquist_frequency(&self) -> Hz {
         Hz(f32::from(self.sample_rate) / 2.0)
     }
+
+    /// The inverse of `PolarFFT::into_folded`, i.e. the full FFT, with the
+    /// normalization undone, and the negative frequencies restored as the
+    /// conjugates of the positive ones
+    pub fn into_cartesian(self) -> CartesianFFT {
+        self.into_cartesian_windowed(1.)
+    }
+
+    /// The inverse of `PolarFFT::into_folded_windowed`, which gives the FFT of
+    /// the windowed signal
+    pub fn into_cartesian_windowed(self, coherent_gain: f32) -> CartesianFFT {
+        let n = self.unfolded_length;
+        let scale = n as f32 * coherent_gain;
+        let mut values = Vec::with_capacity(n);
+        values.extend(self.values.iter().enumerate().map(|(i, (r, theta))| {
+            // (DC and the nyquist frequency have no conjugate, as in
+            // `normalize_folded`)
+            let r = if i == 0 || 2 * i == n {
+                r * scale
+            } else {
+                r * scale / 2.
+            };
+            Complex::from_polar(r, *theta)
+        }));
+        for i in values.len()..n {
+            values.push(values[n - i].conj());
+        }
+        CartesianFFT {
+            values,
+            sample_rate: self.sample_rate,
+        }
+    }
+
+    /// The signal that this is the FFT of (which will include any window
+    /// and padding that was applied before the FFT)
+    pub fn into_signal(self) -> Vec<f32> {
+        self.into_cartesian().into_signal()
+    }
 }
 
 impl AbsDiffEq for FoldedFFT {
@@ -201,7 +516,138 @@ impl AbsDiffEq for FoldedFFT {
 mod tests {
     use super::*;
 
+    use crate::stream::buffer::SampleBuffer;
     use crate::stream::input::SampleRate;
+    use crate::stream::Period;
+
+    #[test]
+    fn hann_coefficients() {
+        assert_abs_diff_eq!(
+            &Window::Hann.coefficients(4)[..],
+            &[0., 0.5, 1., 0.5][..],
+            epsilon = 1e-6
+        );
+        assert_eq!("Hann".parse(), Ok(Window::Hann));
+    }
+
+    #[test]
+    fn window_coefficients() {
+        let peaks = |w: Window| {
+            let c = w.coefficients(8);
+            (c[0], c[4])
+        };
+        let (edge, center) = peaks(Window::Hamming);
+        assert_abs_diff_eq!(edge, 0.08, epsilon = 1e-6);
+        assert_abs_diff_eq!(center, 1., epsilon = 1e-6);
+        let (edge, center) = peaks(Window::BlackmanHarris);
+        assert_abs_diff_eq!(edge, 6e-5, epsilon = 1e-6);
+        assert_abs_diff_eq!(center, 1., epsilon = 1e-6);
+        let (_, center) = peaks(Window::FlatTop);
+        assert_abs_diff_eq!(center, 1., epsilon = 1e-6);
+        let (edge, center) = peaks(Window::Kaiser(8.6));
+        assert_abs_diff_eq!(center, 1., epsilon = 1e-6);
+        assert!(edge < 2e-3);
+        assert_eq!(
+            Window::Kaiser(0.).coefficients(8),
+            Window::Rectangular.coefficients(8)
+        );
+
+        assert_eq!("kaiser:5".parse(), Ok(Window::Kaiser(5.)));
+        assert_eq!("flat-top".parse(), Ok(Window::FlatTop));
+        assert!("kaiser:x".parse::<Window>().is_err());
+        for beta in ["nan", "inf", "-1"] {
+            assert!(format!("kaiser:{}", beta).parse::<Window>().is_err());
+        }
+    }
+
+    #[test]
+    fn real_fft() {
+        let rate = SampleRate::new(64);
+        for n in [64, 63, 2] {
+            let mut signal = (0..).map(|i| ((i * i) as f32 * 0.1).sin() + 0.3);
+            let buf = SampleBuffer::from_mono(rate, &mut signal, n);
+            let period = buf.get_window(Period::new(0, n, rate));
+            let channel = period.get_channel(0);
+
+            let mut ffts = FFTSequence::new(n).with_window(Window::Hann);
+            let expected = ffts
+                .fft(&channel)
+                .into_polar()
+                .into_folded_windowed(ffts.coherent_gain());
+            let folded = ffts.folded_fft(&channel);
+            assert_eq!(folded.values.len(), expected.values.len());
+            assert_eq!(folded.unfolded_length, n);
+            for (a, b) in zip(folded.values, expected.values) {
+                let diff = Complex::from_polar(a.0, a.1) - Complex::from_polar(b.0, b.1);
+                assert!(diff.norm() < 1e-5, "{:?} != {:?}", a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn zero_padding() {
+        // A sinusoid with an amplitude of 0.5, at the center of bin 4 of the
+        // period, which is bin 8 once it's padded to twice the length
+        let rate = SampleRate::new(64);
+        let mut signal = (0..).map(|i| 0.5 * (2. * PI * 4. * i as f32 / 32.).cos());
+        let buf = SampleBuffer::from_mono(rate, &mut signal, 32);
+        let period = buf.get_window(Period::new(0, 32, rate));
+        let channel = period.get_channel(0);
+
+        let mut ffts = FFTSequence::padded(32, 64).with_window(Window::Hann);
+        let folded = ffts.folded_fft(&channel);
+        assert_eq!(folded.values.len(), 33);
+        assert_eq!(folded.frequencies().nth(8), Some(Hz(8.)));
+        assert_abs_diff_eq!(folded.values[8].0, 0.5, epsilon = 1e-3);
+        // (bins in between are interpolated, rather than being zero)
+        assert!(folded.values[7].0 > 0.1);
+
+        let expected = ffts
+            .fft(&channel)
+            .into_polar()
+            .into_folded_windowed(ffts.coherent_gain());
+        assert_abs_diff_eq!(
+            &folded.values.iter().map(|y| y.0).collect::<Vec<_>>()[..],
+            &expected.values.iter().map(|y| y.0).collect::<Vec<_>>()[..],
+            epsilon = 1e-5
+        );
+    }
+
+    #[test]
+    fn inverse_fft() {
+        let rate = SampleRate::new(64);
+        for n in [64, 63] {
+            let signal: Vec<f32> = (0..n).map(|i| ((i * i) as f32 * 0.1).sin() + 0.3).collect();
+            let folded = CartesianFFT::from_real_signal(signal.clone(), rate)
+                .into_polar()
+                .into_folded();
+            assert_eq!(folded.unfolded_length, n);
+            assert_abs_diff_eq!(&folded.into_signal()[..], &signal[..], epsilon = 1e-5);
+        }
+    }
+
+    #[test]
+    fn coherent_gain_correction() {
+        // A sinusoid with an amplitude of 0.5, at the center of bin 8 (the
+        // tolerance allows for leakage from its negative frequency image)
+        let n = 64;
+        let signal = (0..n).map(|i| 0.5 * (2. * PI * 8. * i as f32 / n as f32).cos());
+        for window in [
+            Window::Hann,
+            Window::BlackmanHarris,
+            Window::FlatTop,
+            Window::Kaiser(6.),
+        ] {
+            let windowed = zip(signal.clone(), window.coefficients(n))
+                .map(|(y, w)| y * w)
+                .collect();
+            let folded = CartesianFFT::from_real_signal(windowed, SampleRate::new(64))
+                .into_polar()
+                .into_folded_windowed(window.coherent_gain(n));
+            assert_abs_diff_eq!(folded.values[8].0, 0.5, epsilon = 1e-3);
+        }
+        assert_abs_diff_eq!(Window::Hann.coherent_gain(n), 0.5, epsilon = 1e-6);
+    }
 
     #[test]
     fn polar_unwrap_positive() {
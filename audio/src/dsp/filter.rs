@@ -10,6 +10,10 @@ pub struct LTI {
     inputs: VecDeque<f32>,  // front / 0 is most recent
     outputs: VecDeque<f32>, // front / 0 is most recent
     next: isize,            // index of the next output value, in outputs
+    // (the history and inputs / outputs of a block, oldest first, for
+    // process_block)
+    block_inputs: Vec<f32>,
+    block_outputs: Vec<f32>,
 }
 
 impl LTI {
@@ -26,6 +30,8 @@ impl LTI {
             inputs,
             outputs,
             next: -1,
+            block_inputs: Vec::new(),
+            block_outputs: Vec::new(),
         }
     }
 
@@ -38,16 +44,9 @@ impl LTI {
         }
         self.next = -1;
     }
-}
-
-impl Step for LTI {
-    type Input = f32;
-    type Output = f32;
-
-    fn push_input(&mut self, next_in: f32) {
-        // Make sure we have room to push another output
-        assert!(self.next + 1 < self.outputs.len() as isize);
 
+    /// Computes the next output, and adds it to the front of the outputs
+    fn compute(&mut self, next_in: f32) -> f32 {
         // Add the new input to the input ringbuffer and multiply inputs with
         // feedforward coefficients
         self.inputs.pop_back();
@@ -61,7 +60,6 @@ impl Step for LTI {
         // multiply outputs with feedback coefficients
         self.outputs.pop_back();
         self.outputs.push_front(0f32);
-        self.next += 1;
         // Note that since the front of outputs is 0 (we're carrying the result
         // of feedforward in next_out instead) we can skip the first index here:
         for i in 1..self.feedback.len() {
@@ -69,6 +67,19 @@ impl Step for LTI {
         }
 
         self.outputs[0] = next_out;
+        next_out
+    }
+}
+
+impl Step for LTI {
+    type Input = f32;
+    type Output = f32;
+
+    fn push_input(&mut self, next_in: f32) {
+        // Make sure we have room to push another output
+        assert!(self.next + 1 < self.outputs.len() as isize);
+        self.compute(next_in);
+        self.next += 1;
     }
 
     fn pop_output(&mut self) -> Option<f32> {
@@ -80,6 +91,46 @@ impl Step for LTI {
             None
         }
     }
+
+    /// Computes the same as `compute` for each input, but over contiguous
+    /// slices of the previous and new inputs / outputs, rather than shifting
+    /// the ringbuffers for every sample
+    fn process_block<It: IntoIterator<Item = f32>>(&mut self, input: It, output: &mut Vec<f32>) {
+        // (outputs that haven't been popped come first)
+        while let Some(o) = self.pop_output() {
+            output.push(o);
+        }
+        let (ff_len, fb_len) = (self.feedforward.len(), self.feedback.len());
+        let x = &mut self.block_inputs;
+        x.clear();
+        x.extend(self.inputs.iter().take(ff_len - 1).rev());
+        x.extend(input);
+        let y = &mut self.block_outputs;
+        y.clear();
+        y.extend(self.outputs.iter().take(fb_len - 1).rev());
+
+        for n in ff_len - 1..x.len() {
+            let mut next_out = 0f32;
+            for (i, b) in self.feedforward.iter().enumerate() {
+                next_out += b * x[n - i];
+            }
+            let n = y.len();
+            for (i, a) in self.feedback.iter().enumerate().skip(1) {
+                next_out -= a * y[n - i];
+            }
+            y.push(next_out);
+        }
+        output.extend_from_slice(&y[fb_len - 1..]);
+
+        // The most recent inputs and outputs are the new history (apart from
+        // the oldest of each, which the next sample pushes out anyways)
+        for (h, i) in self.inputs.iter_mut().zip(x.iter().rev()) {
+            *h = *i;
+        }
+        for (h, o) in self.outputs.iter_mut().zip(y.iter().rev()) {
+            *h = *o;
+        }
+    }
 }
 
 #[cfg(test)]
@@ -113,4 +164,36 @@ mod tests {
             &[1., 0., 0.5, 0.2, 0.25, 0.20, 0.165],
         );
     }
+
+    #[test]
+    fn test_process_block() {
+        let input = [1., 0.5, -0.25, 0., 0., 2., -1., 0.125, 0., 0.];
+        let new = || LTI::new(vec![1., -0.3, 0.1], vec![0.5, 0.25, 0.125, 0.0625]);
+        let mut expect = Vec::new();
+        let mut lti = new();
+        for s in input {
+            lti.push_input(s);
+            expect.push(lti.pop_output().unwrap());
+        }
+
+        // In blocks of any size (including smaller than the history, and
+        // empty), alternating with single samples:
+        let mut lti = new();
+        let mut output = Vec::new();
+        for block in [&input[..1], &input[1..3], &[], &input[3..8]] {
+            lti.process_block(block.iter().copied(), &mut output);
+        }
+        assert_response(&mut lti, &input[8..9], &expect[8..9]);
+        lti.process_block(input[9..].iter().copied(), &mut output);
+        output.insert(8, expect[8]);
+        assert_eq!(output, expect);
+
+        // After inputs whose outputs haven't been popped:
+        let mut lti = new();
+        lti.push_input(input[0]);
+        lti.push_input(input[1]);
+        let mut output = Vec::new();
+        lti.process_block(input[2..].iter().copied(), &mut output);
+        assert_eq!(output, expect);
+    }
 }
@@ -5,6 +5,8 @@ use crate::stream::buffer::ChannelPeriod;
 
 pub mod fft;
 pub mod filter;
+pub mod psd;
+pub mod stft;
 
 pub fn rms(period: &ChannelPeriod) -> f32 {
     let sum_sq = period.iter().fold(0.0, |acc, x| acc + x * x);
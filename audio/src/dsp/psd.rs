@@ -0,0 +1,192 @@
+use std::iter::zip;
+
+use crate::dsp::fft::{FFTSequence, Window};
+use crate::stream::buffer::ChannelPeriod;
+use crate::stream::input::SampleRate;
+use crate::Hz;
+
+/// A (one-sided) power spectral density estimate, i.e. the power per Hz (in
+/// FS^2 / Hz) at each frequency from DC to the nyquist frequency, so that the
+/// total power of the signal (its mean square) is the sum of the values times
+/// the bin width
+#[derive(Clone, Debug, PartialEq)]
+pub struct PSD {
+    pub values: Vec<f32>,
+    sample_rate: SampleRate,
+    /// The length of each segment, which determines the bin width
+    segment_len: usize,
+    /// The number of segments that were averaged
+    pub segments: usize,
+}
+
+impl PSD {
+    pub fn frequencies(&self) -> Box<dyn Iterator<Item = Hz> + '_> {
+        let bin_width = f32::from(self.bin_width());
+        Box::new((0..self.values.len()).map(move |i| Hz(i as f32 * bin_width)))
+    }
+
+    pub fn bin_width(&self) -> Hz {
+        Hz(f32::from(self.sample_rate) / self.segment_len as f32)
+    }
+
+    pub fn nyquist_frequency(&self) -> Hz {
+        Hz(f32::from(self.sample_rate) / 2.0)
+    }
+
+    /// The total power, i.e. the mean square of the signal
+    pub fn power(&self) -> f32 {
+        self.values.iter().sum::<f32>() * f32::from(self.bin_width())
+    }
+}
+
+/// Welch's method of estimating the PSD of a signal: averaging the
+/// periodograms (squared FFT magnitudes) of windowed, overlapping segments of
+/// it, which is much less noisy than the periodogram of a single segment.
+pub struct Welch {
+    segment_len: usize,
+    hop: usize,
+    fft: FFTSequence,
+    /// Converts the squared (folded) amplitudes of a segment's FFT to power
+    /// per Hz, apart from dividing by the sample rate, i.e. S1^2 / (2 * S2),
+    /// where S1 and S2 are the sums of the window coefficients and of their
+    /// squares
+    scale: f32,
+    sum: Vec<f32>,
+    segments: usize,
+    sample_rate: Option<SampleRate>,
+}
+
+impl Welch {
+    /// For segments of `segment_len` samples, which start every `hop`
+    /// samples (a Hann window with half the segment length is typical)
+    pub fn new(segment_len: usize, hop: usize, window: Window) -> Welch {
+        assert!(segment_len > 0 && hop > 0);
+        let coefficients = window.coefficients(segment_len);
+        let s1: f32 = coefficients.iter().sum();
+        let s2: f32 = coefficients.iter().map(|w| w * w).sum();
+        Welch {
+            segment_len,
+            hop,
+            fft: FFTSequence::new(segment_len).with_window(window),
+            scale: s1 * s1 / (2. * s2),
+            sum: vec![0.; segment_len / 2 + 1],
+            segments: 0,
+            sample_rate: None,
+        }
+    }
+
+    /// Add a single segment, e.g. one of a stream of periods that start every
+    /// `hop` samples
+    pub fn push_segment(&mut self, segment: &ChannelPeriod) {
+        assert_eq!(
+            *self.sample_rate.get_or_insert(segment.sample_rate()),
+            segment.sample_rate()
+        );
+        let fft = self.fft.folded_fft(segment);
+        let n = self.segment_len;
+        for (i, (sum, (r, _))) in zip(&mut self.sum, &fft.values).enumerate() {
+            let power = r * r * self.scale;
+            // DC and the nyquist frequency have no negative frequency
+            // conjugate, so were scaled by half as much when folded
+            *sum += if i == 0 || 2 * i == n {
+                2. * power
+            } else {
+                power
+            };
+        }
+        self.segments += 1;
+    }
+
+    /// Add each whole segment of a longer period, starting from its start
+    pub fn push_period(&mut self, period: &ChannelPeriod) {
+        if period.len() < self.segment_len {
+            return;
+        }
+        for start in (0..=period.len() - self.segment_len).step_by(self.hop) {
+            self.push_segment(&period.segment(start, self.segment_len));
+        }
+    }
+
+    pub fn segments(&self) -> usize {
+        self.segments
+    }
+
+    /// The average of the segments that have been added, or None if there
+    /// haven't been any
+    pub fn psd(&self) -> Option<PSD> {
+        let sample_rate = self.sample_rate.filter(|_| self.segments > 0)?;
+        let scale = 1. / (f32::from(sample_rate) * self.segments as f32);
+        Some(PSD {
+            values: self.sum.iter().map(|p| p * scale).collect(),
+            sample_rate,
+            segment_len: self.segment_len,
+            segments: self.segments,
+        })
+    }
+
+    /// Forget the segments that have been added
+    pub fn reset(&mut self) {
+        self.sum.fill(0.);
+        self.segments = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f32::consts::PI;
+
+    use super::*;
+    use crate::stream::buffer::SampleBuffer;
+    use crate::stream::Period;
+
+    fn welch_psd(signal: Vec<f32>, welch: &mut Welch) -> PSD {
+        let rate = SampleRate::new(1024);
+        let len = signal.len();
+        let buf = SampleBuffer::from_mono(rate, &mut signal.into_iter(), len);
+        let period = buf.get_window(Period::new(0, len, rate));
+        welch.push_period(&period.get_channel(0));
+        welch.psd().unwrap()
+    }
+
+    #[test]
+    fn sinusoid_power() {
+        // An amplitude of 0.5 (i.e. a power of 0.125) at 100Hz, plus a DC
+        // offset of 0.2 (a power of 0.04)
+        let signal = (0..4096)
+            .map(|i| 0.5 * (2. * PI * 100. * i as f32 / 1024.).sin() + 0.2)
+            .collect();
+        let mut welch = Welch::new(256, 128, Window::Hann);
+        let psd = welch_psd(signal, &mut welch);
+        assert_eq!(psd.segments, 31);
+        assert_eq!(psd.values.len(), 129);
+        assert_eq!(psd.bin_width(), Hz(4.));
+        assert_abs_diff_eq!(psd.power(), 0.165, epsilon = 1e-3);
+        // (the peak is in the bin of the sinusoid's frequency)
+        let peak = (0..psd.values.len())
+            .max_by(|&a, &b| psd.values[a].total_cmp(&psd.values[b]))
+            .unwrap();
+        assert_eq!(psd.frequencies().nth(peak), Some(Hz(100.)));
+
+        welch.reset();
+        assert_eq!(welch.psd(), None);
+    }
+
+    #[test]
+    fn white_noise_density() {
+        // Uniform noise in [-1, 1) has a power of 1/3, spread evenly from 0 to
+        // the nyquist frequency (512Hz)
+        let mut state = 1u32;
+        let signal = (0..65536)
+            .map(|_| {
+                state = state.wrapping_mul(1664525).wrapping_add(1013904223);
+                (state >> 8) as f32 / (1 << 23) as f32 - 1.
+            })
+            .collect();
+        let psd = welch_psd(signal, &mut Welch::new(256, 128, Window::Hann));
+        let density = 1. / 3. / 512.;
+        for p in &psd.values[1..128] {
+            assert_abs_diff_eq!(p, &density, epsilon = 0.25 * density);
+        }
+        assert_abs_diff_eq!(psd.power(), 1. / 3., epsilon = 0.01);
+    }
+}
@@ -0,0 +1,174 @@
+use std::collections::VecDeque;
+use std::iter::zip;
+use std::sync::Arc;
+
+use num_complex::Complex;
+use rustfft::Fft;
+
+use crate::dsp::fft::{plan_fft_inverse, FFTSequence, FoldedFFT, Window};
+use crate::stream::buffer::ChannelPeriod;
+
+/// A short-time Fourier transform, i.e. the FFTs of a sequence of windowed
+/// periods of a signal, which start `hop` samples apart (e.g. the periods from
+/// a `PeriodBuffer`).
+/// The FFTs can be modified (e.g. filtered) and then resynthesized into a
+/// signal by the matching `ISTFT`.
+pub struct STFT {
+    width: usize,
+    hop: usize,
+    window: Window,
+    fft: FFTSequence,
+}
+
+impl STFT {
+    pub fn new(width: usize, hop: usize, window: Window) -> STFT {
+        STFT::padded(width, width, hop, window)
+    }
+
+    /// With each period zero-padded to `padded_width` (see
+    /// `FFTSequence::padded`)
+    pub fn padded(width: usize, padded_width: usize, hop: usize, window: Window) -> STFT {
+        assert!(hop > 0 && hop <= width);
+        STFT {
+            width,
+            hop,
+            window,
+            fft: FFTSequence::padded(width, padded_width).with_window(window),
+        }
+    }
+
+    /// The FFT of the next period, which must start `hop` samples after the
+    /// previous one
+    pub fn transform(&mut self, period: &ChannelPeriod) -> FoldedFFT {
+        self.fft.folded_fft(period)
+    }
+
+    /// The inverse of this transform, for its FFTs (in the same order)
+    pub fn inverse(&self) -> ISTFT {
+        ISTFT::new(self.width, self.hop, self.window, self.fft.coherent_gain())
+    }
+}
+
+/// The inverse of a `STFT`, which resynthesizes a signal from its FFTs by
+/// weighted overlap-add: each period is windowed again (which smooths over
+/// any discontinuities that modifying its FFT introduced), added to the
+/// overlapping periods, and divided by the sum of the squared windows.
+/// This reconstructs an unmodified signal exactly, wherever the windows aren't
+/// (nearly) zero, which is everywhere except for the first and last few
+/// samples of the signal, for windows like Hann that taper to zero.
+pub struct ISTFT {
+    width: usize,
+    hop: usize,
+    window: Vec<f32>,
+    coherent_gain: f32,
+    inverse: Option<Arc<dyn Fft<f32>>>,
+    scratch: Vec<Complex<f32>>,
+    /// The sums of the windowed periods, and of the squared windows, for the
+    /// samples from the start of the last period
+    sum: VecDeque<f32>,
+    norm: VecDeque<f32>,
+}
+
+impl ISTFT {
+    fn new(width: usize, hop: usize, window: Window, coherent_gain: f32) -> ISTFT {
+        ISTFT {
+            width,
+            hop,
+            window: window.coefficients(width),
+            coherent_gain,
+            inverse: None,
+            scratch: Vec::new(),
+            sum: VecDeque::from(vec![0.; width]),
+            norm: VecDeque::from(vec![0.; width]),
+        }
+    }
+
+    /// Add the next FFT, returning the next `hop` samples of the signal,
+    /// which no later periods overlap
+    pub fn push(&mut self, fft: FoldedFFT) -> Vec<f32> {
+        let mut values = fft.into_cartesian_windowed(self.coherent_gain).values;
+        let n = values.len();
+        let inverse = self
+            .inverse
+            .get_or_insert_with(|| plan_fft_inverse(n))
+            .clone();
+        assert_eq!(inverse.len(), n);
+        self.scratch
+            .resize(inverse.get_inplace_scratch_len(), Complex::default());
+        inverse.process_with_scratch(&mut values, &mut self.scratch);
+
+        // (any padding is discarded)
+        for (i, (y, w)) in zip(&values, &self.window).enumerate() {
+            self.sum[i] += y.re / n as f32 * w;
+            self.norm[i] += w * w;
+        }
+        let res = self.take(self.hop);
+        self.sum.resize(self.width, 0.);
+        self.norm.resize(self.width, 0.);
+        res
+    }
+
+    /// The rest of the signal, i.e. the end of the last period, that would
+    /// have been overlapped by later periods
+    pub fn finish(mut self) -> Vec<f32> {
+        self.take(self.width - self.hop)
+    }
+
+    fn take(&mut self, count: usize) -> Vec<f32> {
+        zip(self.sum.drain(..count), self.norm.drain(..count))
+            // (where the windows are nearly zero, so is the signal, and
+            // it can't be recovered accurately)
+            .map(|(y, norm)| if norm > 1e-6 { y / norm } else { 0. })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f32::consts::PI;
+
+    use approx::assert_abs_diff_eq;
+
+    use super::*;
+    use crate::stream::buffer::SampleBuffer;
+    use crate::stream::input::SampleRate;
+    use crate::stream::Period;
+
+    fn resynthesize(mut stft: STFT, signal: &[f32]) -> Vec<f32> {
+        let rate = SampleRate::new(1000);
+        let buf = SampleBuffer::from_mono(rate, &mut signal.iter().copied(), signal.len());
+        let mut istft = stft.inverse();
+        let mut res = Vec::new();
+        for start in (0..=signal.len() - stft.width).step_by(stft.hop) {
+            let period = buf.get_window(Period::new(start, stft.width, rate));
+            res.extend(istft.push(stft.transform(&period.get_channel(0))));
+        }
+        res.extend(istft.finish());
+        res
+    }
+
+    #[test]
+    fn perfect_reconstruction() {
+        let signal: Vec<f32> = (0..1024)
+            .map(|i| {
+                let t = i as f32 / 1000.;
+                (2. * PI * 50. * t).sin() + 0.5 * (2. * PI * 333. * t).cos() + 0.1
+            })
+            .collect();
+
+        // (`margin` is the number of samples at each end that the windows
+        // are too close to zero for)
+        for (stft, margin) in [
+            (STFT::new(256, 256, Window::Rectangular), 0),
+            (STFT::new(256, 64, Window::Hann), 8),
+            (STFT::new(255, 85, Window::Hamming), 0),
+            (STFT::padded(256, 512, 128, Window::Kaiser(6.)), 0),
+        ] {
+            // (up to the end of the last whole period)
+            let res = resynthesize(stft, &signal);
+            assert!(res.len() > signal.len() - 128);
+            let end = res.len() - margin;
+            assert_abs_diff_eq!(&res[margin..end], &signal[margin..end], epsilon = 1e-4);
+        }
+    }
+}
@@ -11,7 +11,8 @@ pub mod synth;
 
 use approx::{AbsDiffEq, RelativeEq};
 use stream::input::Instant;
-pub use stream::transform::FFTResult;
+use stream::executor::AnalysisConfigError;
+pub use stream::transform::{FFTResult, PSDResult};
 
 #[derive(Clone, Debug)]
 pub struct RMSLevels {
@@ -25,8 +26,16 @@ pub struct RMSLevels {
 #[derive(Debug, Clone)]
 pub enum Message {
     AudioStreamClosed,
+    /// Samples were missing from the input for this period (e.g. because
+    /// they were dropped by the device), and have been replaced with silence
+    Dropout(stream::Period),
     FFTResult(FFTResult),
+    /// Power spectral densities, averaged over several periods
+    PSDResult(PSDResult),
     RMSLevels(RMSLevels),
+    /// A `Command::ConfigureAnalysis` was invalid, so the analyses carried on
+    /// as they were
+    AnalysisConfigRejected(AnalysisConfigError),
 }
 
 #[derive(Copy, Clone, Debug, PartialEq)]
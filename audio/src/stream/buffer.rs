@@ -19,6 +19,9 @@ pub struct SampleBuffer {
     sample_rate: SampleRate,
     buffers: Vec<VecDeque<f32>>,
     sample_count: usize,
+    /// The index of the first sample that was pushed since the sample indices
+    /// last restarted
+    first_sample_index: usize,
 }
 
 #[warn(clippy::pedantic)]
@@ -37,6 +40,7 @@ impl SampleBuffer {
             sample_rate,
             buffers,
             sample_count: 0,
+            first_sample_index: 0,
         }
     }
 
@@ -52,20 +56,59 @@ impl SampleBuffer {
     }
 
     fn len(&self) -> usize {
-        cmp::min(self.sample_count, self.max_len)
+        cmp::min(self.sample_count - self.first_sample_index, self.max_len)
     }
 
     fn oldest_sample_index(&self) -> usize {
         self.sample_count - self.len()
     }
 
+    /// Push a frame of samples. If any samples are missing between the end of
+    /// the previous frame and the start of this one (e.g. because they were
+    /// dropped by the input device), they are filled with zeros, so that
+    /// sample indices remain consistent with real time, and the period that
+    /// was missing is returned.
+    /// If the frame starts before the end of the previous one instead (e.g.
+    /// because the input was reopened, and its indices restarted), the buffer
+    /// is cleared, and starts again from this frame, and the discontinuity is
+    /// reported as an empty period at its start.
     #[allow(clippy::missing_panics_doc)]
-    pub fn push(&mut self, f: &Frame) {
+    pub fn push(&mut self, f: &Frame) -> Option<super::Period> {
         assert!(f.channels == self.channels);
         assert!(f.sample_rate == self.sample_rate);
 
+        if f.start_index < self.sample_count {
+            for b in &mut self.buffers {
+                b.clear();
+            }
+            self.sample_count = f.start_index;
+            self.first_sample_index = f.start_index;
+            self.push_samples(f);
+            return Some(super::Period::new(f.start_index, 0, self.sample_rate));
+        }
+
+        let gap = (f.start_index > self.sample_count).then(|| {
+            super::Period::new(
+                self.sample_count,
+                f.start_index - self.sample_count,
+                self.sample_rate,
+            )
+        });
+        if gap.is_some() {
+            // (only what still fits in the buffer actually needs to be filled)
+            let fill = cmp::min(f.start_index - self.sample_count, self.max_len);
+            for ch in 0..usize::from(self.channels) {
+                let _filled = self.push_channel(ch, &mut iter::repeat_n(0., fill), fill);
+            }
+            self.sample_count = f.start_index;
+        }
+        self.push_samples(f);
+        gap
+    }
+
+    fn push_samples(&mut self, f: &Frame) {
         // De-interlace samples into buffers:
-        assert!(f.samples.len() % usize::from(self.channels) == 0);
+        assert!(f.samples.len().is_multiple_of(usize::from(self.channels)));
         self.sample_count += f.samples.len() / usize::from(self.channels);
         for (i, s) in f.samples.iter().enumerate() {
             let ch = i % usize::from(self.channels);
@@ -76,6 +119,19 @@ impl SampleBuffer {
         }
     }
 
+    /// Increase the length of the buffer to `max_len` (if it's shorter),
+    /// keeping the samples that it already has
+    pub fn grow(&mut self, max_len: usize) {
+        if max_len > self.max_len {
+            // (the samples before the current oldest one weren't kept)
+            self.first_sample_index = self.oldest_sample_index();
+            self.max_len = max_len;
+            for b in &mut self.buffers {
+                b.reserve_exact(max_len - b.len());
+            }
+        }
+    }
+
     #[allow(clippy::missing_panics_doc)]
     pub fn push_some_mono<I: Iterator<Item = f32>>(
         &mut self,
@@ -114,8 +170,7 @@ impl SampleBuffer {
 
     #[must_use]
     #[allow(clippy::missing_panics_doc)]
-    #[allow(clippy::needless_lifetimes)] // (false positive - cannot be elided)
-    pub fn get_window<'a>(&'a self, period: super::Period) -> Period<'a> {
+    pub fn get_window(&self, period: super::Period) -> Period<'_> {
         let start_index = period.start().index(self.sample_rate);
         assert!(start_index >= self.oldest_sample_index());
         let end_index = period.end().index(self.sample_rate);
@@ -247,6 +302,26 @@ impl<'a> ChannelPeriod<'a> {
         self.sample_rate
     }
 
+    /// The `len` samples of this period from `start`
+    pub fn segment(&self, start: usize, len: usize) -> ChannelPeriod<'a> {
+        assert!(start + len <= self.len);
+        let (first, second) = self.slices;
+        let slices: (&[f32], &[f32]) = if start + len <= first.len() {
+            (&first[start..start + len], &[])
+        } else if start < first.len() {
+            (&first[start..], &second[..start + len - first.len()])
+        } else {
+            let start = start - first.len();
+            (&second[start..start + len], &[])
+        };
+        ChannelPeriod {
+            slices,
+            sample_rate: self.sample_rate,
+            start_sample_num: self.start_sample_num + start,
+            len,
+        }
+    }
+
     pub fn into_timeseries(self) -> TimeseriesIterator<'a> {
         TimeseriesIterator {
             period: self,
@@ -312,8 +387,22 @@ impl PeriodBuffer {
         }
     }
 
-    pub fn push(&mut self, f: &Frame) {
-        self.buffer.push(f);
+    /// Push a frame of samples, returning the period of any samples that were
+    /// missing before it, or an empty period if its index went backwards (see
+    /// `SampleBuffer::push`), in which case the periods start again from it.
+    pub fn push(&mut self, f: &Frame) -> Option<super::Period> {
+        let restart = f.start_index < self.buffer.sample_count;
+        let gap = self.buffer.push(f);
+        if restart {
+            // The periods start again from the new start of the buffer
+            self.next_period_end = f.start_index + self.period_len;
+        } else if gap.is_some() {
+            // Skip any periods that a long gap pushed out of the buffer
+            // entirely (which would otherwise just be silence, anyways)
+            while self.next_period_end - self.period_len < self.buffer.oldest_sample_index() {
+                self.next_period_end += self.period_stride;
+            }
+        }
         // Verify the start of the buffer hasn't moved past the start of the
         // next period, which might happen if too many samples get pushed
         // between calls to next()
@@ -324,6 +413,32 @@ impl PeriodBuffer {
             next_period_start,
             self.buffer.oldest_sample_index()
         );
+        gap
+    }
+
+    /// Change the length and stride of subsequent periods. The next period
+    /// starts where it would have, but with the new length.
+    pub fn set_period(&mut self, period_len: usize, period_stride: usize) {
+        assert!(period_len <= self.max_period_len());
+        assert!(period_stride > 0);
+        self.next_period_end = self.next_period_end - self.period_len + period_len;
+        self.period_len = period_len;
+        self.period_stride = period_stride;
+    }
+
+    /// The longest period that the underlying buffer can hold
+    pub fn max_period_len(&self) -> usize {
+        self.buffer.max_len
+    }
+
+    /// Make room for longer periods, keeping the samples that have already
+    /// been pushed (see `SampleBuffer::grow`)
+    pub fn grow(&mut self, max_period_len: usize) {
+        self.buffer.grow(max_period_len);
+    }
+
+    pub fn sample_rate(&self) -> SampleRate {
+        self.buffer.sample_rate
     }
 
     pub fn has_next(&self) -> bool {
@@ -332,7 +447,7 @@ impl PeriodBuffer {
 
     /// Get the next available Period, if any
     #[allow(clippy::should_implement_trait)]
-    pub fn next(&mut self) -> Option<Period> {
+    pub fn next(&mut self) -> Option<Period<'_>> {
         if self.has_next() {
             let period = Period {
                 buffer: &self.buffer,
@@ -366,7 +481,7 @@ impl<T: Input<Item = Frame>> BufferedInput<T> {
     }
 
     #[allow(clippy::should_implement_trait)]
-    pub fn next(&mut self) -> Result<Period, InputError> {
+    pub fn next(&mut self) -> Result<Period<'_>, InputError> {
         // Read from the input until a full period is available
         while !self.buffer.has_next() {
             let frame = self.input.read()?;
@@ -399,6 +514,7 @@ pub struct FrameAccumulator {
     sample_rate: SampleRate,
     frame_len: usize,
     samples: Vec<f32>,
+    next_index: usize,
 }
 
 impl FrameAccumulator {
@@ -417,6 +533,7 @@ impl FrameAccumulator {
             sample_rate,
             frame_len,
             samples: Vec::with_capacity(frame_len),
+            next_index: 0,
         }
     }
 
@@ -441,14 +558,45 @@ impl Step for FrameAccumulator {
             let mut res = Frame {
                 channels: self.channels,
                 sample_rate: self.sample_rate,
+                start_index: self.next_index,
                 samples: Vec::with_capacity(self.frame_len),
             };
             mem::swap(&mut res.samples, &mut self.samples);
+            self.next_index = res.end_index();
             Some(res)
         } else {
             None
         }
     }
+
+    fn process_block<It: IntoIterator<Item = f32>>(&mut self, input: It, output: &mut Vec<Frame>) {
+        let mut input = input.into_iter();
+        loop {
+            let remaining = self.frame_len - self.samples.len();
+            self.samples.extend(input.by_ref().take(remaining));
+            match self.pop_output() {
+                Some(frame) => output.push(frame),
+                None => break,
+            }
+        }
+    }
+
+    /// Output the last, partial frame (of the whole samples for every
+    /// channel), if there is one
+    fn flush(&mut self, output: &mut Vec<Frame>) {
+        let len = self.samples.len() - self.samples.len() % usize::from(self.channels);
+        self.samples.truncate(len);
+        if !self.samples.is_empty() {
+            let frame = Frame {
+                channels: self.channels,
+                sample_rate: self.sample_rate,
+                start_index: self.next_index,
+                samples: mem::replace(&mut self.samples, Vec::with_capacity(self.frame_len)),
+            };
+            self.next_index = frame.end_index();
+            output.push(frame);
+        }
+    }
 }
 
 #[cfg(test)]
@@ -462,6 +610,7 @@ mod tests {
         buf.push(&Frame {
             channels: ChannelCount::new(2),
             sample_rate: SampleRate::new(44100),
+            start_index: 0,
             samples: vec![1., 2., 3., 4.],
         });
         assert_eq!(buf.peek_tail(0, 2), [1., 3.]);
@@ -476,12 +625,14 @@ mod tests {
         buf.push(&Frame {
             channels: ChannelCount::new(1),
             sample_rate: SampleRate::new(44100),
+            start_index: 0,
             samples: vec![1.; 3],
         });
         // Add 2 2's, filling the ring, and then replacing the first 1
         buf.push(&Frame {
             channels: ChannelCount::new(1),
             sample_rate: SampleRate::new(44100),
+            start_index: 3,
             samples: vec![2.; 2],
         });
         // The ring should have wrapped around and therefore be split
@@ -516,6 +667,7 @@ mod tests {
         stream.push(&Frame {
             channels: ChannelCount::new(1),
             sample_rate: SampleRate::new(44100),
+            start_index: 0,
             samples: (1..8).map(|x| x as f32).collect(),
         });
 
@@ -540,6 +692,7 @@ mod tests {
         stream.push(&Frame {
             channels: ChannelCount::new(1),
             sample_rate: SampleRate::new(44100),
+            start_index: 7,
             samples: (8..9).map(|x| x as f32).collect(),
         });
 
@@ -563,6 +716,7 @@ mod tests {
         stream.push(&Frame {
             channels: ChannelCount::new(1),
             sample_rate: SampleRate::new(44100),
+            start_index: 0,
             samples: (0..8).map(|x| x as f32).collect(),
         });
 
@@ -584,6 +738,7 @@ mod tests {
         stream.push(&Frame {
             channels: ChannelCount::new(1),
             sample_rate: SampleRate::new(44100),
+            start_index: 8,
             samples: (8..12).map(|x| x as f32).collect(),
         });
 
@@ -593,7 +748,13 @@ mod tests {
             assert_eq!(a, [6., 7.]);
             assert_eq!(b, [8., 9.]);
             let v: Vec<f32> = p.get_channel(0).iter().copied().collect();
-            assert_eq!(v, [6., 7., 8., 9.])
+            assert_eq!(v, [6., 7., 8., 9.]);
+
+            // (and so should segments of it that span the split)
+            let channel = p.get_channel(0);
+            assert_eq!(channel.segment(0, 2).slices, (&[6., 7.][..], &[][..]));
+            assert_eq!(channel.segment(1, 2).slices, (&[7.][..], &[8.][..]));
+            assert_eq!(channel.segment(2, 2).slices, (&[8., 9.][..], &[][..]));
         } else {
             panic!("expected period");
         }
@@ -609,6 +770,106 @@ mod tests {
         }
     }
 
+    #[test]
+    fn gap_zero_fill() {
+        let mut buf: SampleBuffer =
+            SampleBuffer::new(ChannelCount::new(2), SampleRate::new(44100), 100);
+        assert!(buf
+            .push(&Frame {
+                channels: ChannelCount::new(2),
+                sample_rate: SampleRate::new(44100),
+                start_index: 0,
+                samples: vec![1., 2.],
+            })
+            .is_none());
+        // Two (stereo) samples are missing between these frames:
+        let gap = buf.push(&Frame {
+            channels: ChannelCount::new(2),
+            sample_rate: SampleRate::new(44100),
+            start_index: 3,
+            samples: vec![3., 4.],
+        });
+        assert_eq!(
+            gap,
+            Some(crate::stream::Period::new(1, 2, SampleRate::new(44100)))
+        );
+        assert_eq!(buf.sample_count, 4);
+        assert_eq!(buf.peek_tail(0, 4), [1., 0., 0., 3.]);
+        assert_eq!(buf.peek_tail(1, 4), [2., 0., 0., 4.]);
+    }
+
+    #[test]
+    fn periods_after_long_gap() {
+        let mut stream = PeriodBuffer::new(
+            SampleBuffer::new(ChannelCount::new(1), SampleRate::new(44100), 8),
+            4,
+            2,
+        );
+        stream.push(&Frame {
+            channels: ChannelCount::new(1),
+            sample_rate: SampleRate::new(44100),
+            start_index: 0,
+            samples: vec![1., 2.],
+        });
+        assert!(stream.next().is_none());
+
+        // A gap so long that the periods starting at 0 and 2 are pushed out
+        // of the buffer, and the next is the (silent) one starting at 4:
+        assert!(stream
+            .push(&Frame {
+                channels: ChannelCount::new(1),
+                sample_rate: SampleRate::new(44100),
+                start_index: 10,
+                samples: vec![3., 4.],
+            })
+            .is_some());
+        if let Some(p) = stream.next() {
+            assert_eq!(p.start_time(), Instant::new(4, SampleRate::new(44100)));
+            let v: Vec<f32> = p.get_channel(0).iter().copied().collect();
+            assert_eq!(v, [0., 0., 0., 0.]);
+        } else {
+            panic!("expected period");
+        }
+        assert!(stream.next().is_some());
+        if let Some(p) = stream.next() {
+            let v: Vec<f32> = p.get_channel(0).iter().copied().collect();
+            assert_eq!(v, [0., 0., 3., 4.]);
+        } else {
+            panic!("expected period");
+        }
+        assert!(stream.next().is_none());
+    }
+
+    #[test]
+    fn periods_after_restart() {
+        let rate = SampleRate::new(44100);
+        let mut stream = PeriodBuffer::new(SampleBuffer::new(ChannelCount::new(1), rate, 8), 4, 4);
+        let frame = |start_index, samples: Vec<f32>| Frame {
+            channels: ChannelCount::new(1),
+            sample_rate: rate,
+            start_index,
+            samples,
+        };
+        assert!(stream.push(&frame(0, vec![1., 2., 3.])).is_none());
+        assert!(stream.next().is_none());
+
+        // The input's indices restart (e.g. it was reopened), so the partial
+        // period before that is discarded:
+        assert_eq!(
+            stream.push(&frame(0, vec![4., 5.])),
+            Some(crate::stream::Period::new(0, 0, rate))
+        );
+        assert!(stream.push(&frame(2, vec![6., 7., 8.])).is_none());
+        if let Some(p) = stream.next() {
+            assert_eq!(p.start_time(), Instant::new(0, rate));
+            let v: Vec<f32> = p.get_channel(0).iter().copied().collect();
+            assert_eq!(v, [4., 5., 6., 7.]);
+        } else {
+            panic!("expected period");
+        }
+        assert!(stream.next().is_none());
+    }
+
     #[test]
     fn test_frame_accumulator() {
         let mut accum = FrameAccumulator::new(ChannelCount::new(1), SampleRate::new(44100), 4);
@@ -624,6 +885,7 @@ mod tests {
             accum.push_input(i as f32);
         }
         let f = accum.pop_output().unwrap();
+        assert_eq!(f.start_index, 4);
         assert_eq!(f.samples, [4., 5., 6., 7.]);
         assert!(accum.pop_output().is_none());
     }
@@ -0,0 +1,239 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use async_channel::{Receiver, Sender, TrySendError};
+use concurrent_queue::ConcurrentQueue;
+
+use super::output::{Output, OutputError};
+use crate::{FFTResult, Message};
+
+/// Delivers `Message`s from the audio processing thread to the UI without
+/// ever blocking the sender (which would stop it reading its input, and so
+/// cause samples to be dropped) if the UI falls behind. Instead:
+/// - only the latest `FFTResult` is kept, replacing any that the UI hasn't
+///   received yet
+/// - `RMSLevels` are queued, up to a limit, after which the oldest are
+///   dropped
+/// - other messages (e.g. dropouts, PSDs, and the end of the stream) are
+///   rare, and important, so are queued without a limit, and never dropped
+///
+/// (Neither end takes a lock: the queues are lock-free.)
+pub fn channel(queue_len: usize) -> (ResultSender, ResultReceiver) {
+    let shared = Arc::new(Shared {
+        latest_fft: ConcurrentQueue::bounded(1),
+        levels: ConcurrentQueue::bounded(queue_len),
+        events: ConcurrentQueue::unbounded(),
+        skipped_ffts: AtomicUsize::new(0),
+        dropped: AtomicUsize::new(0),
+    });
+    // (a notification that there is something to receive)
+    let (wake_send, wake_recv) = async_channel::bounded(1);
+    (
+        ResultSender {
+            shared: shared.clone(),
+            wake: wake_send,
+        },
+        ResultReceiver {
+            shared,
+            wake: wake_recv,
+        },
+    )
+}
+
+/// The number of messages that were never received, because they were
+/// superseded or dropped
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DeliveryStats {
+    /// `FFTResult`s that were replaced by a more recent one
+    pub skipped_ffts: usize,
+    /// `RMSLevels` that were dropped from a full queue
+    pub dropped: usize,
+}
+
+struct Shared {
+    /// (a queue of one, which each `FFTResult` replaces)
+    latest_fft: ConcurrentQueue<FFTResult>,
+    levels: ConcurrentQueue<Message>,
+    events: ConcurrentQueue<Message>,
+    skipped_ffts: AtomicUsize,
+    dropped: AtomicUsize,
+}
+
+pub struct ResultSender {
+    shared: Arc<Shared>,
+    wake: Sender<()>,
+}
+
+impl Output<Message> for ResultSender {
+    /// Never blocks, but fails if the receiver has been dropped
+    fn push(&mut self, message: Message) -> Result<(), OutputError> {
+        if self.wake.is_closed() {
+            return Err(OutputError::Disconnected);
+        }
+        let shared = &self.shared;
+        // (the queues are never closed, so pushing can't fail)
+        match message {
+            Message::FFTResult(f) => {
+                if let Ok(Some(_)) = shared.latest_fft.force_push(f) {
+                    shared.skipped_ffts.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+            m @ Message::RMSLevels(_) => {
+                if let Ok(Some(_)) = shared.levels.force_push(m) {
+                    shared.dropped.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+            m @ (Message::Dropout(_)
+            | Message::PSDResult(_)
+            | Message::AudioStreamClosed
+            | Message::AnalysisConfigRejected(_)) => {
+                let _e = shared.events.push(m);
+            }
+        }
+        match self.wake.try_send(()) {
+            // (if the notification is full, the receiver hasn't had it yet)
+            Ok(()) | Err(TrySendError::Full(())) => Ok(()),
+            Err(TrySendError::Closed(())) => Err(OutputError::Disconnected),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct ResultReceiver {
+    shared: Arc<Shared>,
+    wake: Receiver<()>,
+}
+
+impl ResultReceiver {
+    /// Wait for the next message, or return None once the sender has been
+    /// dropped and all its messages have been received
+    pub async fn recv(&self) -> Option<Message> {
+        loop {
+            if let Some(m) = self.try_recv() {
+                return Some(m);
+            }
+            if self.wake.recv().await.is_err() {
+                return self.try_recv();
+            }
+        }
+    }
+
+    /// Like `recv`, for when the receiver isn't async
+    pub fn recv_blocking(&self) -> Option<Message> {
+        loop {
+            if let Some(m) = self.try_recv() {
+                return Some(m);
+            }
+            if self.wake.recv_blocking().is_err() {
+                return self.try_recv();
+            }
+        }
+    }
+
+    /// The next message, if any is waiting. The rare messages are received
+    /// first, then queued `RMSLevels`, then the latest `FFTResult`.
+    pub fn try_recv(&self) -> Option<Message> {
+        let shared = &self.shared;
+        shared
+            .events
+            .pop()
+            .or_else(|_| shared.levels.pop())
+            .or_else(|_| shared.latest_fft.pop().map(Message::FFTResult))
+            .ok()
+    }
+
+    pub fn stats(&self) -> DeliveryStats {
+        DeliveryStats {
+            skipped_ffts: self.shared.skipped_ffts.load(Ordering::Relaxed),
+            dropped: self.shared.dropped.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::iter;
+
+    use crate::stream::{Instant, Period, SampleRate};
+    use crate::RMSLevels;
+
+    fn fft(index: usize) -> Message {
+        Message::FFTResult(FFTResult {
+            end_time: Instant::new(index, SampleRate::new(1)),
+            width: 1,
+            sample_rate: SampleRate::new(1),
+            ffts: Vec::new(),
+        })
+    }
+
+    fn rms(index: usize) -> Message {
+        Message::RMSLevels(RMSLevels {
+            time: Instant::new(index, SampleRate::new(1)),
+            values: Vec::new(),
+        })
+    }
+
+    fn time(m: Message) -> usize {
+        match m {
+            Message::FFTResult(f) => f.end_time.index(SampleRate::new(1)),
+            Message::RMSLevels(l) => l.time.index(SampleRate::new(1)),
+            m => panic!("unexpected {:?}", m),
+        }
+    }
+
+    #[test]
+    fn coalesce_and_drop() {
+        let (mut sender, receiver) = channel(2);
+        for i in 0..4 {
+            sender.push(fft(i)).unwrap();
+            sender.push(rms(i)).unwrap();
+        }
+        // The last 2 RMS levels, then the latest FFT:
+        let received: Vec<usize> = iter::from_fn(|| receiver.try_recv()).map(time).collect();
+        assert_eq!(received, [2, 3, 3]);
+        assert_eq!(
+            receiver.stats(),
+            DeliveryStats {
+                skipped_ffts: 3,
+                dropped: 2
+            }
+        );
+
+        sender.push(rms(4)).unwrap();
+        drop(sender);
+        assert_eq!(receiver.recv_blocking().map(time), Some(4));
+        assert!(receiver.recv_blocking().is_none());
+    }
+
+    #[test]
+    fn events_never_dropped() {
+        let (mut sender, receiver) = channel(2);
+        let gap = Period::new(0, 10, SampleRate::new(1));
+        sender.push(Message::Dropout(gap)).unwrap();
+        for i in 0..4 {
+            sender.push(rms(i)).unwrap();
+        }
+        sender.push(Message::AudioStreamClosed).unwrap();
+        // (ahead of the levels that were queued before them)
+        assert!(matches!(receiver.try_recv(), Some(Message::Dropout(p)) if p == gap));
+        assert!(matches!(
+            receiver.try_recv(),
+            Some(Message::AudioStreamClosed)
+        ));
+        let received: Vec<usize> = iter::from_fn(|| receiver.try_recv()).map(time).collect();
+        assert_eq!(received, [2, 3]);
+        assert_eq!(receiver.stats().dropped, 2);
+    }
+
+    #[test]
+    fn receiver_dropped() {
+        let (mut sender, receiver) = channel(2);
+        drop(receiver);
+        assert!(matches!(
+            sender.push(rms(0)),
+            Err(OutputError::Disconnected)
+        ));
+    }
+}
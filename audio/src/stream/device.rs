@@ -0,0 +1,460 @@
+use std::convert::Infallible;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use async_channel::Sender;
+use cpal::traits::{DeviceTrait, HostTrait};
+use cpal::{self, SampleFormat, StreamError, SupportedStreamConfigRange};
+
+use super::realtime::Queue;
+use super::{ChannelCount, SampleRate};
+
+/// Identifies one of a host's input (or output) devices
+#[derive(Clone, Debug, PartialEq)]
+pub enum DeviceId {
+    /// The device with this name, or failing that, the first device whose
+    /// name contains this (ignoring case)
+    Name(String),
+    /// The device at this index in the host's list of input (or output)
+    /// devices, as listed by `hosts()`
+    Index(usize),
+}
+
+impl FromStr for DeviceId {
+    type Err = Infallible;
+
+    /// Numbers are indices, anything else is a name
+    fn from_str(s: &str) -> Result<DeviceId, Infallible> {
+        Ok(match s.parse::<usize>() {
+            Ok(i) => DeviceId::Index(i),
+            Err(_) => DeviceId::Name(s.to_string()),
+        })
+    }
+}
+
+impl fmt::Display for DeviceId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DeviceId::Name(name) => write!(f, "\"{}\"", name),
+            DeviceId::Index(i) => write!(f, "#{}", i),
+        }
+    }
+}
+
+/// Which device to open. The defaults (None) select the default host and its
+/// default device.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DeviceSelector {
+    /// The name of the audio host (i.e. API, e.g. "ALSA" or "JACK")
+    pub host: Option<String>,
+    pub device: Option<DeviceId>,
+}
+
+#[derive(Debug)]
+pub enum DeviceError {
+    HostNotAvailable(String),
+    NoDefaultDevice,
+    DeviceNotFound(DeviceId),
+    DevicesError(cpal::DevicesError),
+}
+
+/// A stream configuration; either what was requested of a device, or what
+/// was actually opened.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DeviceConfig {
+    pub channels: ChannelCount,
+    pub sample_rate: SampleRate,
+    /// The number of samples (per channel) that are transferred in each
+    /// device callback, or None for the device's default
+    pub buffer_size: Option<cpal::FrameCount>,
+}
+
+impl DeviceConfig {
+    pub fn new(channels: ChannelCount, sample_rate: SampleRate) -> DeviceConfig {
+        DeviceConfig {
+            channels,
+            sample_rate,
+            buffer_size: None,
+        }
+    }
+
+    pub fn with_buffer_size(mut self, buffer_size: cpal::FrameCount) -> Self {
+        self.buffer_size = Some(buffer_size);
+        self
+    }
+
+    pub(crate) fn stream_config(&self) -> cpal::StreamConfig {
+        cpal::StreamConfig {
+            channels: u16::from(self.channels),
+            sample_rate: cpal::SampleRate::from(self.sample_rate),
+            buffer_size: self
+                .buffer_size
+                .map_or(cpal::BufferSize::Default, cpal::BufferSize::Fixed),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Direction {
+    Input,
+    Output,
+}
+
+/// A range of stream configurations that a device supports
+#[derive(Clone, Debug)]
+pub struct SupportedConfig {
+    pub channels: ChannelCount,
+    pub min_sample_rate: SampleRate,
+    pub max_sample_rate: SampleRate,
+    pub sample_format: SampleFormat,
+}
+
+impl From<&SupportedStreamConfigRange> for SupportedConfig {
+    fn from(c: &SupportedStreamConfigRange) -> SupportedConfig {
+        SupportedConfig {
+            channels: ChannelCount::new(c.channels()),
+            min_sample_rate: SampleRate::new(c.min_sample_rate().0),
+            max_sample_rate: SampleRate::new(c.max_sample_rate().0),
+            sample_format: c.sample_format(),
+        }
+    }
+}
+
+impl fmt::Display for SupportedConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} channels, {}-{} Hz, {}",
+            u16::from(self.channels),
+            u32::from(self.min_sample_rate),
+            u32::from(self.max_sample_rate),
+            self.sample_format
+        )
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct DeviceInfo {
+    /// The index to use in a `DeviceId::Index`
+    pub index: usize,
+    pub name: String,
+    pub is_default: bool,
+    pub configs: Vec<SupportedConfig>,
+}
+
+#[derive(Clone, Debug)]
+pub struct HostInfo {
+    pub name: String,
+    pub is_default: bool,
+    pub inputs: Vec<DeviceInfo>,
+    pub outputs: Vec<DeviceInfo>,
+}
+
+impl fmt::Display for HostInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let default = |d: bool| if d { " (default)" } else { "" };
+        writeln!(f, "Host: {}{}", self.name, default(self.is_default))?;
+        for (label, devices) in [("Input", &self.inputs), ("Output", &self.outputs)] {
+            writeln!(f, "  {} devices:", label)?;
+            for d in devices {
+                writeln!(f, "    {}: {}{}", d.index, d.name, default(d.is_default))?;
+                for c in &d.configs {
+                    writeln!(f, "        {}", c)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// List the audio hosts that are available on this platform, and their
+/// devices (and the configurations they support).
+pub fn hosts() -> Vec<HostInfo> {
+    let default_host = cpal::default_host().id();
+    cpal::available_hosts()
+        .into_iter()
+        .filter_map(|id| cpal::host_from_id(id).ok())
+        .map(|host| HostInfo {
+            name: host.id().name().to_string(),
+            is_default: host.id() == default_host,
+            inputs: device_infos(&host, Direction::Input),
+            outputs: device_infos(&host, Direction::Output),
+        })
+        .collect()
+}
+
+fn device_infos(host: &cpal::Host, direction: Direction) -> Vec<DeviceInfo> {
+    let default_name = default_device(host, direction).and_then(|d| d.name().ok());
+    devices(host, direction)
+        .unwrap_or_default()
+        .into_iter()
+        .enumerate()
+        .map(|(index, device)| {
+            let name = device.name().unwrap_or_else(|_| String::from("(unknown)"));
+            let configs = match direction {
+                Direction::Input => device
+                    .supported_input_configs()
+                    .map(|cs| cs.map(|c| SupportedConfig::from(&c)).collect()),
+                Direction::Output => device
+                    .supported_output_configs()
+                    .map(|cs| cs.map(|c| SupportedConfig::from(&c)).collect()),
+            };
+            DeviceInfo {
+                index,
+                is_default: default_name.as_ref() == Some(&name),
+                name,
+                configs: configs.unwrap_or_default(),
+            }
+        })
+        .collect()
+}
+
+fn devices(host: &cpal::Host, direction: Direction) -> Result<Vec<cpal::Device>, DeviceError> {
+    match direction {
+        Direction::Input => host.input_devices().map(|ds| ds.collect()),
+        Direction::Output => host.output_devices().map(|ds| ds.collect()),
+    }
+    .map_err(DeviceError::DevicesError)
+}
+
+fn default_device(host: &cpal::Host, direction: Direction) -> Option<cpal::Device> {
+    match direction {
+        Direction::Input => host.default_input_device(),
+        Direction::Output => host.default_output_device(),
+    }
+}
+
+fn find_host(name: Option<&str>) -> Result<cpal::Host, DeviceError> {
+    match name {
+        None => Ok(cpal::default_host()),
+        Some(name) => cpal::available_hosts()
+            .into_iter()
+            .find(|id| id.name().eq_ignore_ascii_case(name))
+            .and_then(|id| cpal::host_from_id(id).ok())
+            .ok_or_else(|| DeviceError::HostNotAvailable(name.to_string())),
+    }
+}
+
+/// Find the selected device
+pub fn find_device(
+    selector: &DeviceSelector,
+    direction: Direction,
+) -> Result<cpal::Device, DeviceError> {
+    let host = find_host(selector.host.as_deref())?;
+    let Some(id) = &selector.device else {
+        return default_device(&host, direction).ok_or(DeviceError::NoDefaultDevice);
+    };
+
+    let mut devices = devices(&host, direction)?;
+    let index = match id {
+        DeviceId::Index(i) => Some(*i).filter(|i| *i < devices.len()),
+        DeviceId::Name(name) => {
+            let names: Vec<String> = devices
+                .iter()
+                .map(|d| d.name().unwrap_or_default())
+                .collect();
+            let lower = name.to_lowercase();
+            names
+                .iter()
+                .position(|n| n == name)
+                .or_else(|| names.iter().position(|n| n.to_lowercase().contains(&lower)))
+        }
+    };
+    match index {
+        Some(i) => Ok(devices.swap_remove(i)),
+        None => Err(DeviceError::DeviceNotFound(id.clone())),
+    }
+}
+
+/// The device sample formats that can be converted to and from the f32
+/// samples in `Frame`s, in order of preference.
+pub(crate) const SAMPLE_FORMATS: [SampleFormat; 8] = [
+    SampleFormat::F32,
+    SampleFormat::F64,
+    SampleFormat::I32,
+    SampleFormat::I24,
+    SampleFormat::I16,
+    SampleFormat::U16,
+    SampleFormat::I8,
+    SampleFormat::U8,
+];
+
+/// Choose the supported configuration that is nearest to the requested one,
+/// and the configuration that should actually be opened. The channel count
+/// must match exactly, but the sample rate and buffer size are clamped to the
+/// supported ranges. Of equally near configs, the earliest of `formats` is
+/// preferred (and other formats are not considered).
+/// Returns None if no config has the requested channel count.
+pub(crate) fn negotiate(
+    requested: &DeviceConfig,
+    supported: &[SupportedStreamConfigRange],
+    formats: &[SampleFormat],
+) -> Option<(SampleFormat, DeviceConfig)> {
+    let requested_rate = u32::from(requested.sample_rate);
+    supported
+        .iter()
+        .filter(|c| c.channels() == u16::from(requested.channels))
+        .filter_map(|c| {
+            let preference = formats.iter().position(|f| *f == c.sample_format())?;
+            let rate = requested_rate.clamp(c.min_sample_rate().0, c.max_sample_rate().0);
+            let buffer_size = match c.buffer_size() {
+                cpal::SupportedBufferSize::Range { min, max } => {
+                    requested.buffer_size.map(|n| n.clamp(*min, *max))
+                }
+                // (requesting a fixed size might fail, so don't)
+                cpal::SupportedBufferSize::Unknown => None,
+            };
+            let config = DeviceConfig {
+                channels: requested.channels,
+                sample_rate: SampleRate::new(rate),
+                buffer_size,
+            };
+            Some((
+                rate.abs_diff(requested_rate),
+                preference,
+                c.sample_format(),
+                config,
+            ))
+        })
+        .min_by_key(|(rate_diff, preference, _, _)| (*rate_diff, *preference))
+        .map(|(_, _, format, config)| (format, config))
+}
+
+/// Make a stream error callback (which is called by a thread owned by the
+/// audio library), that forwards errors to the consumer via `errors`.
+/// If the device has gone away, `frames` is also closed, so that the
+/// consumer isn't left waiting for samples that will never arrive.
+pub(crate) fn forward_errors<T>(
+    errors: Sender<StreamError>,
+    frames: Arc<Queue<T>>,
+) -> impl FnMut(StreamError) + Send + 'static
+where
+    T: Send + 'static,
+{
+    move |err| {
+        if let StreamError::DeviceNotAvailable = err {
+            frames.close();
+        }
+        // If the consumer isn't reading errors fast enough, the oldest are
+        // probably the most interesting anyways:
+        let _ = errors.try_send(err);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_device_id() {
+        assert_eq!("2".parse(), Ok(DeviceId::Index(2)));
+        assert_eq!(
+            "hw:CARD=USB".parse(),
+            Ok(DeviceId::Name(String::from("hw:CARD=USB")))
+        );
+    }
+
+    fn config_range(
+        channels: u16,
+        rates: (u32, u32),
+        buffer_size: cpal::SupportedBufferSize,
+        format: SampleFormat,
+    ) -> SupportedStreamConfigRange {
+        SupportedStreamConfigRange::new(
+            channels,
+            cpal::SampleRate(rates.0),
+            cpal::SampleRate(rates.1),
+            buffer_size,
+            format,
+        )
+    }
+
+    #[test]
+    fn negotiate_nearest() {
+        let buffers = cpal::SupportedBufferSize::Range { min: 64, max: 512 };
+        let supported = [
+            config_range(1, (8000, 48000), buffers, SampleFormat::F32),
+            config_range(2, (8000, 22050), buffers, SampleFormat::F32),
+            config_range(2, (48000, 96000), buffers, SampleFormat::I16),
+            config_range(2, (48000, 96000), buffers, SampleFormat::F32),
+        ];
+        let stereo = ChannelCount::new(2);
+
+        // Supported exactly:
+        let requested = DeviceConfig::new(stereo, SampleRate::new(16000)).with_buffer_size(128);
+        assert_eq!(
+            negotiate(&requested, &supported, &[SampleFormat::F32]),
+            Some((SampleFormat::F32, requested))
+        );
+
+        // 44.1kHz is nearer to 48kHz than 22.05kHz, and the buffer is too big:
+        let requested = DeviceConfig::new(stereo, SampleRate::new(44100)).with_buffer_size(1024);
+        assert_eq!(
+            negotiate(&requested, &supported, &[SampleFormat::F32]),
+            Some((
+                SampleFormat::F32,
+                DeviceConfig::new(stereo, SampleRate::new(48000)).with_buffer_size(512)
+            ))
+        );
+
+        // Format preference:
+        assert_eq!(
+            negotiate(
+                &requested,
+                &supported,
+                &[SampleFormat::I16, SampleFormat::F32]
+            ),
+            Some((
+                SampleFormat::I16,
+                DeviceConfig::new(stereo, SampleRate::new(48000)).with_buffer_size(512)
+            ))
+        );
+
+        // A device that only supports integer formats:
+        let requested = DeviceConfig::new(ChannelCount::new(1), SampleRate::new(48000));
+        let supported = [config_range(1, (48000, 48000), buffers, SampleFormat::I24)];
+        assert_eq!(
+            negotiate(&requested, &supported, &SAMPLE_FORMATS),
+            Some((SampleFormat::I24, requested))
+        );
+        assert_eq!(
+            negotiate(&requested, &supported, &[SampleFormat::F32]),
+            None
+        );
+
+        // No config with 4 channels:
+        let requested = DeviceConfig::new(ChannelCount::new(4), SampleRate::new(44100));
+        assert_eq!(
+            negotiate(&requested, &supported, &[SampleFormat::F32]),
+            None
+        );
+    }
+
+    #[test]
+    fn forwarded_errors() {
+        let (err_send, err_recv) = async_channel::unbounded();
+        let frames = Queue::<()>::new(1);
+        let mut on_error = forward_errors(err_send, frames.clone());
+
+        // Transient errors are just forwarded:
+        on_error(StreamError::BackendSpecific {
+            err: cpal::BackendSpecificError {
+                description: String::from("xrun"),
+            },
+        });
+        assert!(matches!(
+            err_recv.try_recv(),
+            Ok(StreamError::BackendSpecific { .. })
+        ));
+        assert!(!frames.is_closed());
+
+        // But the stream is closed if the device is gone:
+        on_error(StreamError::DeviceNotAvailable);
+        assert!(matches!(
+            err_recv.try_recv(),
+            Ok(StreamError::DeviceNotAvailable)
+        ));
+        assert!(frames.is_closed());
+    }
+}
@@ -1,16 +1,22 @@
-use std::process::exit;
+use std::collections::VecDeque;
+use std::path::PathBuf;
+use std::sync::Arc;
 use std::thread;
+use std::time::{self, Duration};
 // use std::marker::Send;
 
 use async_channel::{Receiver, Sender, TryRecvError};
 
 use super::buffer::{PeriodBuffer, SampleBuffer};
-use super::input::{Input, InputDevice};
-use super::output::OutputDevice;
-use super::pipeline::{Pipeline, Step};
-use super::transform::FFT;
+use super::delivery::{self, ResultReceiver, ResultSender};
+use super::device::{DeviceConfig, DeviceSelector};
+use super::input::{Input, InputDevice, InputError};
+use super::output::{Output, OutputError};
+use super::pipeline::{Branch, BranchId, Identity, Pipeline, ProcessError, Step, Tee};
+use super::transform::{AveragePSD, FFT};
 use super::wav::WavWriter;
 use super::{ChannelCount, Frame, SampleRate};
+use crate::dsp::fft::Window;
 use crate::{dsp, Message, RMSLevels};
 
 // The maximum length of channels passing audio data amongst threads
@@ -18,152 +24,1001 @@ use crate::{dsp, Message, RMSLevels};
 // just going to add latency to the situation.
 pub const CHANNEL_MAX: usize = 16;
 
+/// What is computed from an input stream, which can be changed while an
+/// `Executor` is running
+#[derive(Clone, Debug, PartialEq)]
+pub struct AnalysisConfig {
+    /// The number of samples in each FFT (and RMS) period
+    pub fft_width: usize,
+    /// The number of samples between the starts of subsequent periods (which
+    /// overlap if this is less than `fft_width`)
+    pub hop: usize,
+    /// The number of samples that each period is zero-padded to before its
+    /// FFT, for finer frequency bins (`fft_width`, i.e. no padding, if None)
+    pub padded_width: Option<usize>,
+    pub window: Window,
+    /// Whether to compute `RMSLevels`
+    pub rms: bool,
+    /// The number of periods that each `PSDResult` averages (none are
+    /// computed if this is 0)
+    pub psd_periods: usize,
+    /// The window for the PSD's periods (which is separate from the FFT's,
+    /// since the PSD needs one to be useful)
+    pub psd_window: Window,
+}
+
+/// Why an `AnalysisConfig` can't be used
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AnalysisConfigError {
+    /// The FFT width is 0
+    ZeroWidth,
+    /// The hop between periods is 0
+    ZeroHop,
+    /// The padded width is less than the FFT width
+    PaddedWidthTooShort,
+}
+
+impl AnalysisConfig {
+    /// Check that the analyses can be computed (whether initially, or when
+    /// they're changed while running)
+    pub fn validate(&self) -> Result<(), AnalysisConfigError> {
+        if self.fft_width == 0 {
+            Err(AnalysisConfigError::ZeroWidth)
+        } else if self.hop == 0 {
+            Err(AnalysisConfigError::ZeroHop)
+        } else if self.padded_width.is_some_and(|w| w < self.fft_width) {
+            Err(AnalysisConfigError::PaddedWidthTooShort)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// The length of the buffer that periods are taken from: a whole period,
+    /// plus room for the frames that are pushed before the next one is
+    /// complete (up to a couple of seconds' worth)
+    fn buffer_len(&self, sample_rate: SampleRate) -> usize {
+        self.fft_width + usize::from(sample_rate) * 2
+    }
+
+    fn fft(&self) -> FFT {
+        match self.padded_width {
+            Some(padded_width) => FFT::padded(self.fft_width, padded_width),
+            None => FFT::new(self.fft_width),
+        }
+        .with_window(self.window)
+    }
+
+    fn psd(&self) -> Option<AveragePSD> {
+        (self.psd_periods > 0)
+            .then(|| AveragePSD::new(self.fft_width, self.psd_window, self.psd_periods))
+    }
+}
+
+impl Default for AnalysisConfig {
+    fn default() -> AnalysisConfig {
+        AnalysisConfig {
+            fft_width: 8192,
+            hop: 8192,
+            padded_width: None,
+            window: Window::Rectangular,
+            rms: true,
+            psd_periods: 0,
+            psd_window: Window::Hann,
+        }
+    }
+}
+
+/// The analyses that are computed from an input stream, i.e. the source of
+/// the results that get displayed by the UI.
+struct Analysis {
+    config: AnalysisConfig,
+    /// (created for the first frame, which determines the channel count and
+    /// sample rate)
+    periods: Option<PeriodBuffer>,
+    fft: FFT,
+    psd: Option<AveragePSD>,
+    pending: VecDeque<Message>,
+}
+
+impl Analysis {
+    fn new(config: AnalysisConfig) -> Analysis {
+        Analysis {
+            periods: None,
+            fft: config.fft(),
+            psd: config.psd(),
+            config,
+            pending: VecDeque::new(),
+        }
+    }
+
+    /// Change the analyses, from the next period, or leave them unchanged if
+    /// the configuration is invalid
+    fn configure(&mut self, config: AnalysisConfig) -> Result<(), AnalysisConfigError> {
+        config.validate()?;
+        if let Some(periods) = &mut self.periods {
+            periods.grow(config.buffer_len(periods.sample_rate()));
+            periods.set_period(config.fft_width, config.hop);
+        }
+        if (config.fft_width, config.padded_width, config.window)
+            != (
+                self.config.fft_width,
+                self.config.padded_width,
+                self.config.window,
+            )
+        {
+            self.fft = config.fft();
+        }
+        if (config.fft_width, config.psd_window, config.psd_periods)
+            != (
+                self.config.fft_width,
+                self.config.psd_window,
+                self.config.psd_periods,
+            )
+        {
+            self.psd = config.psd();
+        }
+        self.config = config;
+        Ok(())
+    }
+
+    /// Handle a single frame of samples, returning any results that it
+    /// completed.
+    fn process(&mut self, frame: &Frame) -> Vec<Message> {
+        let config = &self.config;
+        let periods = self.periods.get_or_insert_with(|| {
+            PeriodBuffer::new(
+                SampleBuffer::new(
+                    frame.channels,
+                    frame.sample_rate,
+                    config.buffer_len(frame.sample_rate),
+                ),
+                config.fft_width,
+                config.hop,
+            )
+        });
+        let mut res = Vec::new();
+        if let Some(gap) = periods.push(frame) {
+            if let Some(psd) = &mut self.psd {
+                psd.dropout(gap);
+            }
+            res.push(Message::Dropout(gap));
+        }
+        while let Some(p) = periods.next() {
+            res.push(Message::FFTResult(self.fft.transform(&p)));
+            if let Some(psd) = self.psd.as_mut().and_then(|psd| psd.push(&p)) {
+                res.push(Message::PSDResult(psd));
+            }
+            if config.rms {
+                res.push(Message::RMSLevels(RMSLevels {
+                    time: p.start_time(),
+                    values: p.channels().into_iter().map(|c| dsp::rms(&c)).collect(),
+                }));
+            }
+        }
+        res
+    }
+}
+
+impl Step for Analysis {
+    type Input = Frame;
+    type Output = Message;
+
+    fn push_input(&mut self, frame: Frame) {
+        assert!(self.pending.is_empty());
+        let results = self.process(&frame);
+        self.pending.extend(results);
+    }
+
+    fn pop_output(&mut self) -> Option<Message> {
+        self.pending.pop_front()
+    }
+}
+
+/// Where the `Executor` should record its input to
+#[derive(Clone, Debug)]
+pub enum RecordTo {
+    /// The given file (which is overwritten if it already exists)
+    File(PathBuf),
+    /// A new file in the given directory, named for the time it was started
+    Timestamped(PathBuf),
+}
+
+impl Default for RecordTo {
+    /// A new session-<time>.wav in the current directory
+    fn default() -> RecordTo {
+        RecordTo::Timestamped(PathBuf::from("."))
+    }
+}
+
+/// Requests that can be sent to a running `Executor`
+#[derive(Clone, Debug)]
+pub enum Command {
+    /// Start recording the input (ending any recording that is in progress)
+    StartRecording(RecordTo),
+    StopRecording,
+    /// Change the analyses (without interrupting the input). The
+    /// configuration should be checked with `AnalysisConfig::validate` before
+    /// it's sent: if it's invalid, it's ignored, and
+    /// `Message::AnalysisConfigRejected` is sent with the results.
+    ConfigureAnalysis(AnalysisConfig),
+}
+
+/// The `Pipeline` that an `Executor` runs: the input is copied to each of
+/// the analyses (and the recording, if there is one)
+type AnalysisPipeline = Pipeline<InputDevice, Identity<Frame>, Tee<Frame>>;
+
+/// The branch of an `AnalysisPipeline` that computes the `Analysis`
+type AnalysisBranch = Branch<Analysis, ResultSender>;
+
+/// The analyzer's audio processing: waits for samples from the input device,
+/// computes the results we want, and sends those results to the UI thread for
+/// display (optionally also recording the input), on a `PipelineExecutor`.
 pub struct Executor {
+    device: DeviceSelector,
     channels: ChannelCount,
     sample_rate: SampleRate,
-    writer: WavWriter,
-    periods: PeriodBuffer,
-    fft: FFT,
-    sender: Sender<Message>,
+    recording: Option<RecordTo>,
+    analysis: AnalysisConfig,
 }
 
-/// Encapsulates the audio processing thread, which waits for samples from the
-/// input device, computes the results we want, and sends those results to
-/// the UI thread for display.
 impl Executor {
-    /// Create an executor that will send display updates via the given Sender
-    pub fn new(
-        sender: Sender<Message>,
-        channels: ChannelCount,
-        sample_rate: SampleRate,
-    ) -> Executor {
+    /// Create an executor that will open an input device with (or as near
+    /// as possible to) the given configuration
+    pub fn new(channels: ChannelCount, sample_rate: SampleRate) -> Executor {
         Executor {
+            device: DeviceSelector::default(),
             channels,
             sample_rate,
-            writer: WavWriter::new(channels, sample_rate),
-            periods: PeriodBuffer::new(
-                SampleBuffer::new(channels, sample_rate, usize::from(sample_rate) * 2),
-                8192,
-                8192,
-            ),
-            fft: FFT::new(8192),
-            sender,
+            recording: Some(RecordTo::default()),
+            analysis: AnalysisConfig::default(),
         }
     }
 
-    /// Handle a single frame of samples received from the input device
-    fn process(&mut self, frame: &Frame) -> Vec<Message> {
-        let mut res = Vec::new();
-        self.writer.push(frame).expect("session.wav write error");
-        self.periods.push(frame);
-        while let Some(p) = self.periods.next() {
-            res.push(Message::FFTResult(self.fft.transform(&p)));
-            res.push(Message::RMSLevels(RMSLevels {
-                time: p.start_time(),
-                values: p.channels().into_iter().map(|c| dsp::rms(&c)).collect(),
-            }));
-        }
-        res
+    /// Which input device to open (instead of the default)
+    pub fn with_input_device(mut self, device: DeviceSelector) -> Self {
+        self.device = device;
+        self
     }
 
-    /// The main loop of the audio processing thread
-    fn run<T: Input<Item = Frame>>(mut self, mut input: T) {
-        loop {
-            match input.read() {
-                Ok(f) => {
-                    for m in self.process(&f) {
-                        if self.sender.send_blocking(m).is_err() {
-                            println!("Executor exit: UI closed.");
-                            return;
-                        }
+    /// Where to record the input to, from when the executor starts (or None
+    /// to not record unless requested by a `Command`).
+    pub fn with_recording(mut self, recording: Option<RecordTo>) -> Self {
+        self.recording = recording;
+        self
+    }
+
+    /// What to compute from the input, initially (see
+    /// `Command::ConfigureAnalysis`), or an error if it's invalid
+    pub fn with_analysis(mut self, analysis: AnalysisConfig) -> Result<Self, AnalysisConfigError> {
+        analysis.validate()?;
+        self.analysis = analysis;
+        Ok(self)
+    }
+
+    /// Spawn a new thread to run this executor, returning a Sender for
+    /// `Command`s to it, and a Receiver for its results (which never blocks
+    /// the executor, see `delivery::channel`)
+    pub fn start(
+        self,
+    ) -> (
+        Sender<Control<Command>>,
+        ResultReceiver,
+        thread::JoinHandle<ExecutorResult>,
+    ) {
+        let device = self.device;
+        let requested = DeviceConfig::new(self.channels, self.sample_rate)
+            .with_buffer_size(InputDevice::DEFAULT_BUFFER);
+        let (sender, messages) = delivery::channel(CHANNEL_MAX);
+        let mut analyses = Tee::default();
+        let analysis = analyses.add(Branch::new(Analysis::new(self.analysis), sender));
+        let setup = move |_: Sender<()>| {
+            let input = InputDevice::open_nearest(&device, &requested)
+                .map_err(ProcessError::InputDeviceError)?;
+            let config = input.config();
+            if config != requested {
+                println!("Executor: opened input with {:?}", config);
+            }
+            Ok(Pipeline::new(input, Identity::default(), analyses))
+        };
+
+        let mut recording = None;
+        let update = move |pipeline: &mut AnalysisPipeline, cmd| match cmd {
+            Command::StartRecording(to) => {
+                stop_recording(pipeline, recording.take());
+                recording = start_recording(pipeline, to);
+            }
+            Command::StopRecording => stop_recording(pipeline, recording.take()),
+            Command::ConfigureAnalysis(config) => {
+                let output = pipeline.output_mut();
+                if let Some(branch) = output.branch_mut::<AnalysisBranch>(analysis) {
+                    if let Err(e) = branch.step_mut().configure(config) {
+                        println!("Executor: invalid analysis configuration: {:?}", e);
+                        let _e = branch.output_mut().push(Message::AnalysisConfigRejected(e));
                     }
                 }
-                Err(_) => {
-                    println!("Executor exit: audio input closed.");
-                    let _e = self.sender.send_blocking(Message::AudioStreamClosed);
-                    return;
-                }
             }
+        };
+
+        let handle = PipelineExecutor::start(setup, update);
+        if let Some(to) = self.recording {
+            let _e = handle
+                .commands
+                .try_send(Control::Command(Command::StartRecording(to)));
         }
+        (handle.commands, messages, handle.thread)
     }
+}
 
-    /// Spawn a new thread to run this executor
-    pub fn start(self) -> thread::JoinHandle<()> {
-        thread::spawn(move || {
-            // cpal::StreamTrait isn't Send, so the input device needs to
-            // be opened on the executor thread.
-            match InputDevice::new(self.channels, self.sample_rate) {
-                Ok(input) => self.run(input),
-                Err(err) => {
-                    // TODO: propagate this, instead of terminating
-                    println!("Failed to open input: {:?}", err);
-                    exit(-1);
-                }
-            }
+/// Add a branch to record the input to the given file, returning its id
+fn start_recording(pipeline: &mut AnalysisPipeline, to: RecordTo) -> Option<BranchId> {
+    let config = pipeline.input_mut().config();
+    let writer = match &to {
+        RecordTo::File(path) => WavWriter::new(path, config.channels, config.sample_rate),
+        RecordTo::Timestamped(dir) => {
+            WavWriter::timestamped(dir, config.channels, config.sample_rate)
+        }
+    };
+    match writer {
+        Ok(w) => {
+            println!("Executor: recording to {}", w.path().display());
+            Some(pipeline.output_mut().add(w))
+        }
+        // Analysis can carry on regardless:
+        Err(e) => {
+            println!("Executor: failed to start recording to {:?}: {}", to, e);
+            None
+        }
+    }
+}
+
+fn stop_recording(pipeline: &mut AnalysisPipeline, recording: Option<BranchId>) {
+    // (the recording may already have been removed, after a write error)
+    if let Some(mut w) = recording.and_then(|id| pipeline.output_mut().remove(id)) {
+        if let Err(e) = w.flush() {
+            println!("Executor: error finishing recording: {:?}", e);
+        }
+    }
+}
+
+/// Computes the same results as `Executor`, but from any input (e.g. a
+/// `WavReader` or an iterator), on the calling thread, as fast as the input
+/// can be read. The results are produced as an Iterator, which ends when the
+/// input does.
+pub struct OfflineExecutor<T: Input<Item = Frame>> {
+    input: T,
+    /// (the frame read by `new`, until it's analysed)
+    first: Option<Frame>,
+    analysis: Analysis,
+    error: Option<InputError>,
+}
+
+impl<T: Input<Item = Frame>> OfflineExecutor<T> {
+    /// The OfflineExecutor will get its sample rate and channel count from
+    /// the input
+    pub fn new(mut input: T) -> Result<OfflineExecutor<T>, InputError> {
+        let frame = input.read()?;
+        Ok(OfflineExecutor {
+            input,
+            first: Some(frame),
+            analysis: Analysis::new(AnalysisConfig::default()),
+            error: None,
         })
     }
+
+    /// What to compute from the input (see `Executor::with_analysis`), or an
+    /// error if it's invalid
+    pub fn with_analysis(mut self, analysis: AnalysisConfig) -> Result<Self, AnalysisConfigError> {
+        analysis.validate()?;
+        self.analysis = Analysis::new(analysis);
+        Ok(self)
+    }
+
+    /// The error that ended the input, if it ended for any reason other than
+    /// reaching the end of the stream.
+    pub fn error(&self) -> Option<&InputError> {
+        match &self.error {
+            Some(InputError::StreamEnded) => None,
+            e => e.as_ref(),
+        }
+    }
+}
+
+impl<T: Input<Item = Frame>> Iterator for OfflineExecutor<T> {
+    type Item = Message;
+
+    fn next(&mut self) -> Option<Message> {
+        loop {
+            if let Some(m) = self.analysis.pop_output() {
+                return Some(m);
+            } else if self.error.is_some() {
+                return None;
+            }
+            match self.first.take().map_or_else(|| self.input.read(), Ok) {
+                Ok(f) => self.analysis.push_input(f),
+                Err(e) => self.error = Some(e),
+            }
+        }
+    }
 }
 
-/// TODO: this should be merged with Executor
-pub struct PipelineExecutor<I, S, Cmd>
+/// How well a `PipelineExecutor`'s input and output are keeping up
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Health {
+    /// The number of times that input was lost because it wasn't read in time
+    pub overruns: usize,
+    /// The number of times that the output ran out of data (e.g. and so
+    /// played silence)
+    pub underruns: usize,
+    /// The number of items waiting to be output
+    pub queue_len: usize,
+}
+
+/// Reports from a running `PipelineExecutor`
+#[derive(Clone, Debug)]
+pub enum Status {
+    /// Sent periodically (see `PipelineExecutor::HEALTH_INTERVAL`), and when
+    /// the executor stops
+    Health(Health),
+    /// An error that the executor carried on after (e.g. a transient device
+    /// error, or a branch of a `Tee` failing)
+    Error(Arc<ProcessError>),
+    Paused,
+    Playing,
+    /// The executor has stopped (after flushing its output)
+    Stopped(StopReason),
+    /// The executor stopped because of an error reading its input or pushing
+    /// to its output
+    Failed(Arc<ProcessError>),
+}
+
+/// Why a `PipelineExecutor` stopped (other than because of an error)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StopReason {
+    /// The input ended (e.g. at the end of a file)
+    EndOfStream,
+    /// It was sent `Control::Stop`
+    Stop,
+    /// The command Sender was dropped
+    Disconnected,
+}
+
+/// What a `PipelineExecutor`'s thread returns when it stops
+pub type ExecutorResult = Result<StopReason, Arc<ProcessError>>;
+
+/// Requests that can be sent to any `PipelineExecutor`: transport controls,
+/// or a command for its update function
+#[derive(Clone, Debug)]
+pub enum Control<Cmd> {
+    /// Resume processing after `Pause`
+    Play,
+    /// Stop reading the input, and pushing to the output, until `Play`.
+    /// (Devices keep running: an `InputDevice` will overrun, and an
+    /// `OutputDevice` will play silence.)
+    Pause,
+    /// Flush the steps (e.g. their partial frames) and the output, and stop
+    /// the executor
+    Stop,
+    /// Flush the output (e.g. to make sure a recording is complete so far)
+    Flush,
+    Command(Cmd),
+}
+
+/// The caller's side of a running `PipelineExecutor`
+pub struct ExecutorHandle<Cmd, Msg> {
+    /// Controls and commands for the executor (dropping this stops it)
+    pub commands: Sender<Control<Cmd>>,
+    /// The pipeline's results
+    pub results: Receiver<Msg>,
+    /// Reports on how the executor is running. These never block the
+    /// executor: if they aren't received, the oldest are dropped.
+    pub status: Receiver<Status>,
+    pub thread: thread::JoinHandle<ExecutorResult>,
+}
+
+/// Runs a `Pipeline` on its own thread: reading from any `Input`, processing
+/// it with any `Step`s, and pushing the results to any `Output` (e.g. an
+/// `OutputDevice` to play them, or a `Tee` of analyses).
+/// `Cmd`s sent to the executor are applied to the pipeline between blocks by
+/// an update function, and the pipeline can send results back to the caller,
+/// as whatever type of message it likes.
+pub struct PipelineExecutor<I, S, O, Cmd>
 where
     I: Input,
     S: Step<Input = I::Item, Output = Frame>,
-    Cmd: Send + 'static,
+    O: Output,
 {
-    pipeline: Pipeline<I, S, OutputDevice>,
-    receiver: Receiver<Cmd>,
+    pipeline: Pipeline<I, S, O>,
+    receiver: Receiver<Control<Cmd>>,
     #[allow(clippy::type_complexity)]
-    update: Box<dyn Fn(&mut Pipeline<I, S, OutputDevice>, Cmd)>,
+    update: Box<dyn FnMut(&mut Pipeline<I, S, O>, Cmd)>,
+    status: Sender<Status>,
+    paused: bool,
 }
 
-impl<I, S, Cmd> PipelineExecutor<I, S, Cmd>
+impl<I, S, O, Cmd> PipelineExecutor<I, S, O, Cmd>
 where
-    I: Input + Send + 'static,
-    S: Step<Input = I::Item, Output = Frame> + Send + 'static,
+    I: Input,
+    S: Step<Input = I::Item, Output = Frame>,
+    O: Output,
     Cmd: Send + 'static,
 {
-    pub fn start<UpdateFn>(
-        channels: ChannelCount,
-        sample_rate: SampleRate,
-        input: I,
-        step: S,
-        update: Box<UpdateFn>,
-    ) -> (Sender<Cmd>, Receiver<()>, thread::JoinHandle<()>)
+    /// The maximum number of inputs to process at once, between checking for
+    /// commands
+    pub const BLOCK_LEN: usize = 256;
+
+    /// How often to send `Status::Health`
+    pub const HEALTH_INTERVAL: Duration = Duration::from_millis(250);
+
+    /// Spawn a new thread, and call `setup` on it to create the pipeline
+    /// (since audio devices can't be moved between threads), with a Sender
+    /// for the pipeline's results. If that fails (e.g. to open a device), the
+    /// executor reports `Status::Failed`, and its thread returns the error.
+    /// `Control::Command`s sent to the returned handle are passed to
+    /// `update`. The executor runs until the input ends, it is stopped, the
+    /// command Sender is dropped, or an error that it can't carry on after.
+    pub fn start<Msg, Setup, UpdateFn>(setup: Setup, update: UpdateFn) -> ExecutorHandle<Cmd, Msg>
     where
-        UpdateFn: Fn(&mut Pipeline<I, S, OutputDevice>, Cmd) + Send + 'static,
+        Msg: Send + 'static,
+        Setup: FnOnce(Sender<Msg>) -> Result<Pipeline<I, S, O>, ProcessError> + Send + 'static,
+        UpdateFn: FnMut(&mut Pipeline<I, S, O>, Cmd) + Send + 'static,
     {
         let (req_send, req_recv) = async_channel::bounded(CHANNEL_MAX);
-        let (_msg_send, msg_recv) = async_channel::bounded(CHANNEL_MAX);
-        (
-            req_send,
-            msg_recv,
-            thread::spawn(move || {
+        let (msg_send, msg_recv) = async_channel::bounded(CHANNEL_MAX);
+        let (status_send, status_recv) = async_channel::bounded(CHANNEL_MAX);
+        ExecutorHandle {
+            commands: req_send,
+            results: msg_recv,
+            status: status_recv,
+            thread: thread::spawn(move || {
+                let pipeline = match setup(msg_send) {
+                    Ok(pipeline) => pipeline,
+                    Err(e) => {
+                        println!("Executor exit: setup failed: {:?}", e);
+                        let e = Arc::new(e);
+                        let _e = status_send.force_send(Status::Failed(e.clone()));
+                        return Err(e);
+                    }
+                };
                 let mut executor = PipelineExecutor {
-                    pipeline: Pipeline::new(
-                        input,
-                        step,
-                        OutputDevice::new(channels, sample_rate).unwrap(),
-                    ),
+                    pipeline,
                     receiver: req_recv,
-                    update,
+                    update: Box::new(update),
+                    status: status_send,
+                    paused: false,
                 };
-                executor.run();
+                executor.run()
             }),
-        )
+        }
     }
 
-    fn run(&mut self) {
+    fn run(&mut self) -> ExecutorResult {
+        let mut last_health = time::Instant::now();
+        let mut reported = Health::default();
         loop {
-            match self.receiver.try_recv() {
-                Ok(cmd) => (self.update)(&mut self.pipeline, cmd),
-                Err(TryRecvError::Empty) => (),
-                Err(_) => {
-                    println!("Executor: UI exited");
-                    break;
+            loop {
+                // (while paused, wait for the next control instead of
+                // processing)
+                let control = if self.paused {
+                    self.receiver
+                        .recv_blocking()
+                        .map_err(|_| TryRecvError::Closed)
+                } else {
+                    self.receiver.try_recv()
+                };
+                let result = match control {
+                    Ok(Control::Play) => {
+                        self.paused = false;
+                        self.report(Status::Playing);
+                        Ok(())
+                    }
+                    Ok(Control::Pause) => {
+                        self.paused = true;
+                        self.report(Status::Paused);
+                        Ok(())
+                    }
+                    Ok(Control::Stop) => return self.finish(StopReason::Stop),
+                    Ok(Control::Flush) => self.flush(),
+                    Ok(Control::Command(cmd)) => {
+                        (self.update)(&mut self.pipeline, cmd);
+                        Ok(())
+                    }
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Closed) => {
+                        println!("Executor exit: UI exited");
+                        return self.finish(StopReason::Disconnected);
+                    }
+                };
+                self.check(result)?;
+            }
+            let result = self.pipeline.process_block(Self::BLOCK_LEN);
+            if last_health.elapsed() >= Self::HEALTH_INTERVAL {
+                let health = self.health();
+                self.log_health(&reported, &health);
+                self.report(Status::Health(health));
+                reported = health;
+                last_health = time::Instant::now();
+            }
+            match result {
+                Err(ProcessError::InputError(InputError::StreamEnded)) => {
+                    println!("Executor exit: end of input");
+                    return self.finish(StopReason::EndOfStream);
                 }
+                result => self.check(result)?,
             }
-            self.pipeline.process_once().unwrap();
         }
     }
+
+    fn flush(&mut self) -> Result<(), ProcessError> {
+        self.pipeline
+            .output_mut()
+            .flush()
+            .map_err(ProcessError::OutputError)
+    }
+
+    /// Report an error that the executor can carry on after, or stop after
+    /// one that it can't
+    fn check(&self, result: Result<(), ProcessError>) -> Result<(), Arc<ProcessError>> {
+        match result {
+            Ok(()) => Ok(()),
+            // (if the device has gone away, the next read or push will fail)
+            Err(
+                e @ (ProcessError::InputError(InputError::StreamError(_))
+                | ProcessError::OutputError(OutputError::StreamError(_))
+                // The other branches carry on:
+                | ProcessError::OutputError(OutputError::BranchesFailed(_))),
+            ) => {
+                println!("Executor: carrying on after error: {:?}", e);
+                self.report(Status::Error(Arc::new(e)));
+                Ok(())
+            }
+            Err(e) => {
+                println!("Executor exit: {:?}", e);
+                let e = Arc::new(e);
+                self.report(Status::Health(self.health()));
+                self.report(Status::Failed(e.clone()));
+                Err(e)
+            }
+        }
+    }
+
+    /// Flush the steps (e.g. a partial frame, or a filter's tail) and then
+    /// the output, and report that the executor has stopped
+    fn finish(&mut self, reason: StopReason) -> ExecutorResult {
+        let result = self.pipeline.flush_step();
+        self.check(result)?;
+        let result = self.flush();
+        self.check(result)?;
+        self.report(Status::Health(self.health()));
+        self.report(Status::Stopped(reason));
+        Ok(reason)
+    }
+
+    /// Log any overruns or underruns since the last report (which is done
+    /// here, rather than by the device callbacks, since they shouldn't do
+    /// I/O)
+    fn log_health(&self, last: &Health, health: &Health) {
+        let overruns = health.overruns.saturating_sub(last.overruns);
+        let underruns = health.underruns.saturating_sub(last.underruns);
+        if overruns > 0 || underruns > 0 {
+            println!(
+                "Executor: {} input overruns and {} output underruns",
+                overruns, underruns
+            );
+        }
+    }
+
+    fn health(&self) -> Health {
+        Health {
+            overruns: self.pipeline.input().overruns(),
+            underruns: self.pipeline.output().underruns(),
+            queue_len: self.pipeline.output().queue_len(),
+        }
+    }
+
+    fn report(&self, status: Status) {
+        // (replacing the oldest status if the channel is full, and ignoring
+        // whether anyone is listening)
+        let _e = self.status.force_send(status);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::iter;
+
+    use crate::stream::buffer::FrameAccumulator;
+    use crate::stream::input::InputAdapter;
+    use crate::stream::output::OpenError;
+    use crate::synth::SinIterator;
+
+    #[test]
+    fn offline_analysis() {
+        let sample_rate = SampleRate::new(44100);
+        // 4 FFT periods' worth of a full scale sinusoid:
+        let input = InputAdapter::new(
+            SinIterator::new(sample_rate, 1000., 0.).take(4 * 8192),
+            FrameAccumulator::new(ChannelCount::new(1), sample_rate, 1024),
+        );
+        let executor = OfflineExecutor::new(input).unwrap();
+
+        let mut fft_count = 0;
+        let mut rms_count = 0;
+        for m in executor {
+            match m {
+                Message::FFTResult(f) => {
+                    fft_count += 1;
+                    assert_eq!(f.width, 8192);
+                    assert_eq!(f.ffts.len(), 1);
+                }
+                Message::RMSLevels(l) => {
+                    rms_count += 1;
+                    assert_abs_diff_eq!(l.values[0], 1.0 / 2f32.sqrt(), epsilon = 1e-3);
+                }
+                Message::AudioStreamClosed
+                | Message::Dropout(_)
+                | Message::PSDResult(_)
+                | Message::AnalysisConfigRejected(_) => {
+                    panic!("unexpected message")
+                }
+            }
+        }
+        assert_eq!(fft_count, 4);
+        assert_eq!(rms_count, 4);
+    }
+
+    #[test]
+    fn offline_analysis_config() {
+        let sample_rate = SampleRate::new(44100);
+        let input = || {
+            InputAdapter::new(
+                SinIterator::new(sample_rate, 1000., 0.).take(4 * 8192),
+                FrameAccumulator::new(ChannelCount::new(1), sample_rate, 1024),
+            )
+        };
+        let config = AnalysisConfig {
+            fft_width: 4096,
+            hop: 0,
+            rms: false,
+            ..AnalysisConfig::default()
+        };
+        assert!(matches!(
+            OfflineExecutor::new(input())
+                .unwrap()
+                .with_analysis(config.clone()),
+            Err(AnalysisConfigError::ZeroHop)
+        ));
+        let config = AnalysisConfig {
+            hop: 4096,
+            ..config
+        };
+        let executor = OfflineExecutor::new(input())
+            .unwrap()
+            .with_analysis(config)
+            .unwrap();
+        let widths: Vec<usize> = executor
+            .map(|m| match m {
+                Message::FFTResult(f) => f.width,
+                m => panic!("unexpected {:?}", m),
+            })
+            .collect();
+        assert_eq!(widths, [4096; 8]);
+    }
+
+    #[test]
+    fn reconfigure_analysis() {
+        let sample_rate = SampleRate::new(44100);
+        let mut frames = InputAdapter::new(
+            SinIterator::new(sample_rate, 1000., 0.),
+            FrameAccumulator::new(ChannelCount::new(1), sample_rate, 1024),
+        );
+        let mut analysis = Analysis::new(AnalysisConfig::default());
+        let mut results = Vec::new();
+        for _ in 0..8 {
+            analysis.process_block([frames.read().unwrap()], &mut results);
+        }
+        assert!(
+            matches!(&results[..], [Message::FFTResult(f), Message::RMSLevels(_)] if f.width == 8192)
+        );
+
+        // Shorter, overlapping periods, starting from where the next would have:
+        results.clear();
+        analysis
+            .configure(AnalysisConfig {
+                fft_width: 4096,
+                hop: 2048,
+                padded_width: Some(8192),
+                window: Window::Hann,
+                rms: false,
+                psd_periods: 2,
+                psd_window: Window::Hann,
+            })
+            .unwrap();
+        for _ in 0..8 {
+            analysis.process_block([frames.read().unwrap()], &mut results);
+        }
+        let end_times: Vec<usize> = results
+            .iter()
+            .map(|m| match m {
+                Message::FFTResult(f) => {
+                    assert_eq!(f.width, 4096);
+                    assert_eq!(f.ffts[0].values.len(), 4097);
+                    f.end_time.index(sample_rate)
+                }
+                Message::PSDResult(p) => {
+                    assert_eq!(p.start_time.index(sample_rate), 8192);
+                    assert_eq!(p.psds[0].segments, 2);
+                    // (the power of a full scale sinusoid)
+                    assert_abs_diff_eq!(p.psds[0].power(), 0.5, epsilon = 1e-3);
+                    p.end_time.index(sample_rate)
+                }
+                m => panic!("unexpected {:?}", m),
+            })
+            .collect();
+        // (the PSD averages the first two periods)
+        assert_eq!(end_times, [12288, 14336, 14336, 16384]);
+
+        // Invalid configurations are rejected, and ignored:
+        let config = analysis.config.clone();
+        assert_eq!(
+            analysis.configure(AnalysisConfig {
+                hop: 0,
+                ..config.clone()
+            }),
+            Err(AnalysisConfigError::ZeroHop)
+        );
+        assert_eq!(
+            analysis.configure(AnalysisConfig {
+                padded_width: Some(2048),
+                ..config.clone()
+            }),
+            Err(AnalysisConfigError::PaddedWidthTooShort)
+        );
+        assert_eq!(analysis.config, config);
+
+        // And periods longer than the buffer was made for grow it:
+        results.clear();
+        analysis
+            .configure(AnalysisConfig {
+                fft_width: 4 * 44100,
+                hop: 4 * 44100,
+                padded_width: None,
+                window: Window::Rectangular,
+                rms: true,
+                psd_periods: 0,
+                psd_window: Window::Hann,
+            })
+            .unwrap();
+        for _ in 0..200 {
+            analysis.process_block([frames.read().unwrap()], &mut results);
+        }
+        assert!(matches!(
+            &results[..],
+            [Message::FFTResult(f), Message::RMSLevels(_)] if f.width == 4 * 44100
+        ));
+    }
+
+    #[test]
+    fn psd_after_dropout() {
+        let sample_rate = SampleRate::new(44100);
+        let mut frames = InputAdapter::new(
+            SinIterator::new(sample_rate, 1000., 0.),
+            FrameAccumulator::new(ChannelCount::new(1), sample_rate, 1024),
+        );
+        let mut analysis = Analysis::new(AnalysisConfig {
+            fft_width: 2048,
+            hop: 2048,
+            rms: false,
+            psd_periods: 2,
+            ..AnalysisConfig::default()
+        });
+        let mut results = Vec::new();
+        // A period, and then a frame that's missing from the next:
+        for _ in 0..3 {
+            analysis.process_block([frames.read().unwrap()], &mut results);
+        }
+        let _missing = frames.read().unwrap();
+        for _ in 0..8 {
+            analysis.process_block([frames.read().unwrap()], &mut results);
+        }
+        // The PSDs only average the periods after the dropout:
+        let psds: Vec<_> = results
+            .iter()
+            .filter_map(|m| match m {
+                Message::PSDResult(p) => Some(p),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(psds.len(), 2);
+        assert_eq!(psds[0].start_time.index(sample_rate), 4096);
+        assert_eq!(psds[0].end_time.index(sample_rate), 8192);
+        assert_abs_diff_eq!(psds[0].psds[0].power(), 0.5, epsilon = 1e-3);
+    }
+
+    #[test]
+    fn pipeline_executor() {
+        let sample_rate = SampleRate::new(44100);
+        // A finite input (ending with a partial frame, which is flushed), with
+        // the output being the results channel:
+        let handle = PipelineExecutor::start(
+            move |sender| {
+                Ok(Pipeline::new(
+                    SinIterator::new(sample_rate, 1000., 0.).take(10 * 1024 + 100),
+                    FrameAccumulator::new(ChannelCount::new(1), sample_rate, 1024),
+                    sender,
+                ))
+            },
+            |_, ()| (),
+        );
+        let mut next_index = 0;
+        while let Ok(frame) = handle.results.recv_blocking() {
+            assert_eq!(frame.start_index, next_index);
+            next_index = frame.end_index();
+        }
+        assert_eq!(next_index, 10 * 1024 + 100);
+        assert!(matches!(
+            handle.thread.join().unwrap(),
+            Ok(StopReason::EndOfStream)
+        ));
+
+        // The executor reports its final health, then why it stopped:
+        let statuses: Vec<Status> = iter::from_fn(|| handle.status.try_recv().ok()).collect();
+        assert!(matches!(
+            &statuses[statuses.len() - 2..],
+            [
+                Status::Health(Health {
+                    overruns: 0,
+                    underruns: 0,
+                    ..
+                }),
+                Status::Stopped(StopReason::EndOfStream)
+            ]
+        ));
+        drop(handle.commands);
+    }
+
+    #[test]
+    fn failed_setup() {
+        // (e.g. the output device isn't available)
+        let handle = PipelineExecutor::<SinIterator, FrameAccumulator, Sender<Frame>, ()>::start(
+            |_: Sender<Frame>| Err(ProcessError::OpenError(OpenError::ConfigNotAvailable)),
+            |_, ()| (),
+        );
+        assert!(matches!(
+            handle.thread.join().unwrap(),
+            Err(e) if matches!(*e, ProcessError::OpenError(OpenError::ConfigNotAvailable))
+        ));
+        assert!(matches!(
+            handle.status.try_recv(),
+            Ok(Status::Failed(e)) if matches!(*e, ProcessError::OpenError(_))
+        ));
+        assert!(handle.results.recv_blocking().is_err());
+    }
+
+    #[test]
+    fn stop_executor() {
+        let sample_rate = SampleRate::new(44100);
+        // (an endless input)
+        let handle = PipelineExecutor::start(
+            move |sender| {
+                Ok(Pipeline::new(
+                    SinIterator::new(sample_rate, 1000., 0.),
+                    FrameAccumulator::new(ChannelCount::new(1), sample_rate, 1024),
+                    sender,
+                ))
+            },
+            |_, ()| (),
+        );
+        handle.commands.send_blocking(Control::Pause).unwrap();
+        handle.commands.send_blocking(Control::Command(())).unwrap();
+        handle.commands.send_blocking(Control::Play).unwrap();
+        handle.commands.send_blocking(Control::Stop).unwrap();
+        // (the executor may be blocked on its output until this is drained)
+        while handle.results.recv_blocking().is_ok() {}
+        assert!(matches!(
+            handle.thread.join().unwrap(),
+            Ok(StopReason::Stop)
+        ));
+    }
 }
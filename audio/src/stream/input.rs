@@ -1,10 +1,15 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
 use async_channel;
-use async_channel::{Receiver, TryRecvError, TrySendError};
-use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
-use cpal::{self, SupportedStreamConfigRange};
+use async_channel::Receiver;
+use cpal::traits::{DeviceTrait, StreamTrait};
+use cpal::{self, FromSample, SampleFormat, SizedSample, StreamError, SupportedStreamConfigRange};
 
+use super::device::{self, DeviceConfig, DeviceError, DeviceSelector, Direction};
 use super::executor::CHANNEL_MAX;
 use super::pipeline::Step;
+use super::realtime::{self, Queue};
 
 // TODO: move other users to use the new location of these:
 pub use super::{ChannelCount, Frame, Instant, SampleRate};
@@ -13,12 +18,22 @@ pub use super::{ChannelCount, Frame, Instant, SampleRate};
 pub enum InputError {
     DeviceClosed,
     StreamEnded,
+    /// An error reported by the device (which may be transient; if the
+    /// device is gone, subsequent reads will return `DeviceClosed`)
+    StreamError(cpal::StreamError),
+    WavError(hound::Error),
 }
 
 pub trait Input {
     type Item;
     fn read(&mut self) -> Result<Self::Item, InputError>;
     fn try_read(&mut self) -> Result<Option<Self::Item>, InputError>;
+
+    /// The number of times that input was lost because it wasn't read in time
+    /// (for inputs that are produced in real time)
+    fn overruns(&self) -> usize {
+        0
+    }
 }
 
 impl<T, I: Iterator<Item = T>> Input for I {
@@ -68,115 +83,256 @@ impl<I: Input, S: Step<Input = I::Item>> Input for InputAdapter<I, S> {
             }
         }
     }
+
+    fn overruns(&self) -> usize {
+        self.input.overruns()
+    }
 }
 
 #[derive(Debug)]
 pub enum InputDeviceError {
+    DeviceError(DeviceError),
+    SupportedConfigsError(cpal::SupportedStreamConfigsError),
     UnsupportedChannelCount(ChannelCount, Vec<SupportedStreamConfigRange>),
+    UnsupportedSampleRate(SampleRate, Vec<SupportedStreamConfigRange>),
+    BuildStreamError(cpal::BuildStreamError),
+    PlayStreamError(cpal::PlayStreamError),
 }
 
 /// Opens a stream from an audio input device, receives sample data callbacks
-/// (which are called by a thread owned by the audio library), and sends the
-/// data to consuming threads via `async_channel`.
+/// (which are called by a thread owned by the audio library), and passes the
+/// data to the thread that opened the device, which reads it as `Frame`s.
+/// The callbacks fill buffers from a pool, which the `InputDevice` replaces
+/// as frames are read, so that the callbacks never allocate.
 pub struct InputDevice {
-    pub frames: Receiver<Frame>,
+    frames: Arc<Queue<Frame>>,
+    /// (empty buffers, for the callback to fill)
+    buffers: Arc<Queue<Vec<f32>>>,
+    buffer_len: usize,
+    errors: Receiver<cpal::StreamError>,
+    config: DeviceConfig,
+    /// (counted by the input callback)
+    overruns: Arc<AtomicUsize>,
     // This owns the input callbacks (and will close the stream when dropped).
     _stream: Box<dyn StreamTrait>,
 }
 
 impl InputDevice {
+    /// The buffer size to request, if none is specified. cpal has a warning
+    /// that some devices default to very large buffers, resulting in high
+    /// input latency.
+    pub const DEFAULT_BUFFER: cpal::FrameCount = 1024;
+
+    /// Open the default input device
     pub fn new(
         channels: ChannelCount,
         sample_rate: SampleRate,
     ) -> Result<InputDevice, InputDeviceError> {
-        let host = cpal::default_host();
-        // TODO: some way of selecting from available devices?
-        let device = host.default_input_device().unwrap();
-
-        // Find supported config for the desired number of channels:
-        let mut supported: Option<cpal::SupportedStreamConfigRange> = None;
-        for c in device.supported_input_configs().unwrap() {
-            if c.channels() == channels.0 {
-                supported = Some(c);
-                break;
-            }
-        }
+        InputDevice::open(
+            &DeviceSelector::default(),
+            &DeviceConfig::new(channels, sample_rate).with_buffer_size(Self::DEFAULT_BUFFER),
+        )
+    }
+
+    /// Open the selected device with exactly the requested channel count and
+    /// sample rate (although the buffer size may be adjusted to what the
+    /// device supports; see `config()`).
+    pub fn open(
+        selector: &DeviceSelector,
+        requested: &DeviceConfig,
+    ) -> Result<InputDevice, InputDeviceError> {
+        InputDevice::open_with(selector, requested, true)
+    }
+
+    /// Open the selected device with the requested channel count, and the
+    /// supported sample rate and buffer size that are nearest to those
+    /// requested. Check `config()` for what was actually opened.
+    pub fn open_nearest(
+        selector: &DeviceSelector,
+        requested: &DeviceConfig,
+    ) -> Result<InputDevice, InputDeviceError> {
+        InputDevice::open_with(selector, requested, false)
+    }
+
+    /// The configuration that the device was actually opened with
+    pub fn config(&self) -> DeviceConfig {
+        self.config
+    }
+
+    fn open_with(
+        selector: &DeviceSelector,
+        requested: &DeviceConfig,
+        exact_sample_rate: bool,
+    ) -> Result<InputDevice, InputDeviceError> {
+        let device = device::find_device(selector, Direction::Input)
+            .map_err(InputDeviceError::DeviceError)?;
+        let supported: Vec<SupportedStreamConfigRange> = device
+            .supported_input_configs()
+            .map_err(InputDeviceError::SupportedConfigsError)?
+            .collect();
 
-        if supported.is_none() {
+        let Some((format, config)) =
+            device::negotiate(requested, &supported, &device::SAMPLE_FORMATS)
+        else {
             return Err(InputDeviceError::UnsupportedChannelCount(
-                channels,
-                device
-                    .supported_input_configs()
-                    .map_or(Vec::new(), |opts| opts.into_iter().collect()),
+                requested.channels,
+                supported,
+            ));
+        };
+        if exact_sample_rate && config.sample_rate != requested.sample_rate {
+            return Err(InputDeviceError::UnsupportedSampleRate(
+                requested.sample_rate,
+                supported,
             ));
         }
-
-        // TODO: make sure the desired sample rate is actually supported
-        // TODO: set a buffer size (within supported range) instead of just
-        // using the default? cpal has a warning that some devices default
-        // to very large buffers resulting in high input latency.
-        let config = supported
-            .unwrap()
-            .with_sample_rate(cpal::SampleRate(sample_rate.0));
-
-        let (sender, receiver) = async_channel::bounded(CHANNEL_MAX);
+        // (a callback's samples are split into several frames if they don't
+        // fit in one buffer)
+        let buffer_len = config.buffer_size.unwrap_or(Self::DEFAULT_BUFFER) as usize
+            * usize::from(config.channels);
+        let producer = Producer {
+            frames: Queue::new(CHANNEL_MAX),
+            buffers: realtime::buffer_pool(CHANNEL_MAX, buffer_len),
+            buffer_len,
+            overruns: Arc::new(AtomicUsize::new(0)),
+        };
+        let (frames, buffers, overruns) = (
+            producer.frames.clone(),
+            producer.buffers.clone(),
+            producer.overruns.clone(),
+        );
+        let (err_sender, err_receiver) = async_channel::bounded(CHANNEL_MAX);
+        let on_error = device::forward_errors(err_sender, frames.clone());
         let stream = Box::new(
-            device
-                .build_input_stream(
-                    &config.config(),
-                    move |data: &[f32], _: &cpal::InputCallbackInfo| {
-                        match sender.try_send(Frame {
-                            channels,
-                            sample_rate,
-                            samples: Vec::from(data),
-                        }) {
-                            Err(TrySendError::Full(_)) => {
-                                // TODO: does this need any more handling?
-                                // The consumer could notice a drop by watching
-                                // the frame number.
-                                println!("InputDevice: dropped {} samples", data.len());
-                            }
-                            Err(TrySendError::Closed(_)) => {
-                                // TODO: close the stream?
-                                println!("No receiver for {} samples", data.len());
-                            }
-                            Ok(()) => {}
-                        }
-                    },
-                    move |_err| {
-                        // Can these be transient?
-                        todo!();
-                    },
-                    None, // blocking
-                )
-                .unwrap(),
+            match format {
+                SampleFormat::F32 => build_stream::<f32, _>(&device, &config, producer, on_error),
+                SampleFormat::F64 => build_stream::<f64, _>(&device, &config, producer, on_error),
+                SampleFormat::I32 => build_stream::<i32, _>(&device, &config, producer, on_error),
+                SampleFormat::I24 => {
+                    build_stream::<cpal::I24, _>(&device, &config, producer, on_error)
+                }
+                SampleFormat::I16 => build_stream::<i16, _>(&device, &config, producer, on_error),
+                SampleFormat::U16 => build_stream::<u16, _>(&device, &config, producer, on_error),
+                SampleFormat::I8 => build_stream::<i8, _>(&device, &config, producer, on_error),
+                SampleFormat::U8 => build_stream::<u8, _>(&device, &config, producer, on_error),
+                _ => unreachable!("negotiated an unsupported format: {}", format),
+            }
+            .map_err(InputDeviceError::BuildStreamError)?,
         );
         // Apparently *some* platforms don't automatically start the stream
         // so this is possibly necessary.
-        stream.play().expect("Failed to start stream");
+        stream.play().map_err(InputDeviceError::PlayStreamError)?;
 
         Ok(InputDevice {
-            frames: receiver,
+            frames,
+            buffers,
+            buffer_len,
+            errors: err_receiver,
+            config,
+            overruns,
             _stream: stream,
         })
     }
+
+    /// Convert the oldest error reported by the device (if any) to an
+    /// InputError, or default to DeviceClosed.
+    fn closed_error(&self) -> InputError {
+        self.errors
+            .try_recv()
+            .map_or(InputError::DeviceClosed, InputError::StreamError)
+    }
+}
+
+/// The input callback's side of an `InputDevice`
+struct Producer {
+    frames: Arc<Queue<Frame>>,
+    buffers: Arc<Queue<Vec<f32>>>,
+    /// (the capacity of each buffer)
+    buffer_len: usize,
+    /// The number of frames that were dropped because there wasn't a buffer
+    /// for them, i.e. because the consumer wasn't keeping up
+    overruns: Arc<AtomicUsize>,
+}
+
+/// Build an input stream that converts samples of type T (i.e. the device's
+/// sample format) to the f32 samples of Frames
+fn build_stream<T, E>(
+    device: &cpal::Device,
+    config: &DeviceConfig,
+    producer: Producer,
+    on_error: E,
+) -> Result<cpal::Stream, cpal::BuildStreamError>
+where
+    T: SizedSample,
+    f32: FromSample<T>,
+    E: FnMut(StreamError) + Send + 'static,
+{
+    let DeviceConfig {
+        channels,
+        sample_rate,
+        ..
+    } = *config;
+    let mut next_index = 0;
+    device.build_input_stream(
+        &config.stream_config(),
+        move |data: &[T], _: &cpal::InputCallbackInfo| {
+            for chunk in data.chunks(producer.buffer_len) {
+                let start_index = next_index;
+                // (this advances even if the frame is dropped, so that the
+                // consumer can tell that samples are missing)
+                next_index += chunk.len() / usize::from(channels);
+                let Some(mut samples) = producer.buffers.pop() else {
+                    producer.overruns.fetch_add(1, Ordering::Relaxed);
+                    continue;
+                };
+                samples.clear();
+                samples.extend(chunk.iter().map(|s| s.to_sample::<f32>()));
+                let frame = Frame {
+                    channels,
+                    sample_rate,
+                    start_index,
+                    samples,
+                };
+                // There's room for as many frames as there are buffers, so
+                // this only fails if the device has been closed (and then the
+                // buffer is kept, rather than deallocated here)
+                if let Err(frame) = producer.frames.push(frame) {
+                    let _full = producer.buffers.push(frame.samples);
+                }
+            }
+        },
+        on_error,
+        None, // blocking
+    )
 }
 
 impl Input for InputDevice {
     type Item = Frame;
 
     fn read(&mut self) -> Result<Frame, InputError> {
-        match self.frames.recv_blocking() {
-            Ok(f) => Ok(f),
-            Err(_) => Err(InputError::DeviceClosed),
+        loop {
+            if let Some(frame) = self.try_read()? {
+                return Ok(frame);
+            }
+            self.frames.wait();
         }
     }
 
     fn try_read(&mut self) -> Result<Option<Frame>, InputError> {
-        match self.frames.try_recv() {
-            Ok(f) => Ok(Some(f)),
-            Err(TryRecvError::Empty) => Ok(None),
-            Err(TryRecvError::Closed) => Err(InputError::DeviceClosed),
+        if let Ok(e) = self.errors.try_recv() {
+            return Err(InputError::StreamError(e));
         }
+        match self.frames.pop() {
+            Some(frame) => {
+                // The frame keeps its buffer, and the callback gets a new one
+                let _full = self.buffers.push(Vec::with_capacity(self.buffer_len));
+                Ok(Some(frame))
+            }
+            None if self.frames.is_closed() => Err(self.closed_error()),
+            None => Ok(None),
+        }
+    }
+
+    fn overruns(&self) -> usize {
+        self.overruns.load(Ordering::Relaxed)
     }
 }
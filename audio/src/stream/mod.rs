@@ -5,10 +5,15 @@ use std::time;
 use cpal::{self};
 
 pub mod buffer;
+pub mod delivery;
+pub mod device;
 pub mod executor;
 pub mod input;
 pub mod output;
 pub mod pipeline;
+mod realtime;
+pub mod resample;
+pub mod routing;
 pub mod transform;
 pub mod wav;
 
@@ -91,6 +96,10 @@ impl Instant {
         assert_eq!(self.sample_rate, rate);
         self.sample_index
     }
+
+    pub fn sample_rate(&self) -> SampleRate {
+        self.sample_rate
+    }
 }
 
 impl PartialOrd for Instant {
@@ -209,12 +218,25 @@ impl Period {
 }
 
 /// A batch of samples received from an input device.
+#[derive(Clone, Debug)]
 pub struct Frame {
     pub channels: ChannelCount,
     pub sample_rate: SampleRate,
+    /// The index (i.e. the number of samples, per channel, since the start of
+    /// the stream) of the first sample in this frame. If this is greater than
+    /// the end index of the previous frame, samples were dropped in between.
+    pub start_index: usize,
+    /// Interlaced samples, for each channel
     pub samples: Vec<f32>,
 }
 
+impl Frame {
+    /// The index of the sample after the last sample in this frame
+    pub fn end_index(&self) -> usize {
+        self.start_index + self.samples.len() / usize::from(self.channels)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -1,10 +1,17 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
 use async_channel;
-use async_channel::{Receiver, Sender, TryRecvError};
+use async_channel::{Receiver, Sender};
 use cpal;
-use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
-use cpal::SampleFormat;
+use cpal::traits::{DeviceTrait, StreamTrait};
+use cpal::{FromSample, SampleFormat, SizedSample, StreamError};
 
 use crate::stream;
+use crate::stream::device::{self, DeviceConfig, DeviceError, DeviceSelector, Direction};
+use crate::stream::executor::CHANNEL_MAX;
+use crate::stream::pipeline::BranchId;
+use crate::stream::realtime::Queue;
 use crate::stream::Frame;
 
 pub use async_channel::SendError;
@@ -12,20 +19,68 @@ pub use async_channel::SendError;
 #[derive(Debug)]
 pub enum OutputError {
     DeviceClosed,
+    /// An error reported by the device (which may be transient; if the
+    /// device is gone, subsequent pushes will return `DeviceClosed`)
+    StreamError(cpal::StreamError),
+    /// The receiving end of a channel has been dropped
+    Disconnected,
+    WavError(hound::Error),
+    /// Some of the branches of a `Tee` failed (and have been removed)
+    BranchesFailed(Vec<(BranchId, OutputError)>),
+}
+
+/// A sink for a stream of items (generally `Frame`s)
+pub trait Output<T = Frame> {
+    fn push(&mut self, item: T) -> Result<(), OutputError>;
+
+    /// Make sure that everything that has been pushed has been written (for
+    /// outputs that buffer their input)
+    fn flush(&mut self) -> Result<(), OutputError> {
+        Ok(())
+    }
+
+    /// The number of times that the output needed items before they had been
+    /// pushed (for outputs that consume items in real time)
+    fn underruns(&self) -> usize {
+        0
+    }
+
+    /// The number of items that have been pushed but not yet consumed
+    fn queue_len(&self) -> usize {
+        0
+    }
 }
 
-pub trait Output {
-    fn push(&mut self, frame: Frame) -> Result<(), OutputError>;
+/// Sends each item to another thread, blocking while the channel is full
+impl<T> Output<T> for Sender<T> {
+    fn push(&mut self, item: T) -> Result<(), OutputError> {
+        self.send_blocking(item)
+            .map_err(|_| OutputError::Disconnected)
+    }
+
+    fn queue_len(&self) -> usize {
+        self.len()
+    }
 }
 
+/// Opens a stream to an audio output device, and passes the frames that are
+/// pushed to it to the device's callbacks (which are called by a thread owned
+/// by the audio library).
+/// The callbacks never allocate or deallocate: the buffers of frames that
+/// have been played are passed back, and deallocated by `push`.
 pub struct OutputDevice {
-    sender: Sender<Frame>,
+    frames: Arc<Queue<Frame>>,
+    /// (the buffers of frames that have been played)
+    spent: Arc<Queue<Vec<f32>>>,
+    errors: Receiver<cpal::StreamError>,
+    /// (counted by the output callback)
+    underruns: Arc<AtomicUsize>,
     _stream: Box<dyn StreamTrait>,
 }
 
 #[derive(Debug)]
 pub enum OpenError {
-    DeviceNotAvailable,
+    DeviceNotAvailable(DeviceError),
     ConfigNotAvailable,
     BuildStreamError(cpal::BuildStreamError),
     PlayStreamError,
@@ -48,85 +103,178 @@ impl OutputDevice {
     /// increases memory use and output latency.
     const MAX_FRAME_QUEUE_LEN: usize = 4;
 
+    /// Open the default output device
     pub fn new(
         channels: stream::ChannelCount,
         sample_rate: stream::SampleRate,
     ) -> Result<OutputDevice, OpenError> {
-        let host = cpal::default_host();
-        let device = host
-            .default_output_device()
-            .ok_or(OpenError::DeviceNotAvailable)?;
+        OutputDevice::open(&DeviceSelector::default(), channels, sample_rate)
+    }
+
+    pub fn open(
+        selector: &DeviceSelector,
+        channels: stream::ChannelCount,
+        sample_rate: stream::SampleRate,
+    ) -> Result<OutputDevice, OpenError> {
+        let device = device::find_device(selector, Direction::Output)
+            .map_err(OpenError::DeviceNotAvailable)?;
 
-        let mut supported: Option<cpal::SupportedStreamConfigRange> = None;
-        for c in device
+        let supported: Vec<cpal::SupportedStreamConfigRange> = device
             .supported_output_configs()
             .or(Err(OpenError::ConfigNotAvailable))?
-        {
-            if let SampleFormat::F32 = c.sample_format() {
-                if c.channels() == u16::from(channels) {
-                    supported = Some(c);
-                    break;
+            .collect();
+        let requested =
+            DeviceConfig::new(channels, sample_rate).with_buffer_size(OutputDevice::DEVICE_BUFFER);
+        let (format, config) = device::negotiate(&requested, &supported, &device::SAMPLE_FORMATS)
+            .filter(|(_, config)| config.sample_rate == sample_rate)
+            .ok_or(OpenError::ConfigNotAvailable)?;
+
+        let frames = Queue::new(OutputDevice::MAX_FRAME_QUEUE_LEN);
+        // (with room for everything that can be pushed between calls to
+        // `push`, i.e. the queued frames, and the one being played)
+        let spent = Queue::new(OutputDevice::MAX_FRAME_QUEUE_LEN + 1);
+        let receiver = FrameReceiver::new(channels, sample_rate, frames.clone(), spent.clone());
+        let (err_sender, err_receiver) = async_channel::bounded(CHANNEL_MAX);
+        let on_error = device::forward_errors(err_sender, frames.clone());
+        let config = config.stream_config();
+        let underruns = Arc::new(AtomicUsize::new(0));
+        let counter = underruns.clone();
+        let stream = Box::new(
+            match format {
+                SampleFormat::F32 => {
+                    build_stream::<f32, _>(&device, &config, receiver, counter, on_error)
+                }
+                SampleFormat::F64 => {
+                    build_stream::<f64, _>(&device, &config, receiver, counter, on_error)
+                }
+                SampleFormat::I32 => {
+                    build_stream::<i32, _>(&device, &config, receiver, counter, on_error)
+                }
+                SampleFormat::I24 => {
+                    build_stream::<cpal::I24, _>(&device, &config, receiver, counter, on_error)
+                }
+                SampleFormat::I16 => {
+                    build_stream::<i16, _>(&device, &config, receiver, counter, on_error)
+                }
+                SampleFormat::U16 => {
+                    build_stream::<u16, _>(&device, &config, receiver, counter, on_error)
+                }
+                SampleFormat::I8 => {
+                    build_stream::<i8, _>(&device, &config, receiver, counter, on_error)
                 }
+                SampleFormat::U8 => {
+                    build_stream::<u8, _>(&device, &config, receiver, counter, on_error)
+                }
+                _ => unreachable!("negotiated an unsupported format: {}", format),
             }
-        }
-
-        // TODO: what if the desired configuration isn't supported?
-        let mut config = supported
-            .ok_or(OpenError::ConfigNotAvailable)?
-            .with_sample_rate(cpal::SampleRate::from(sample_rate))
-            .config();
-        config.buffer_size = cpal::BufferSize::Fixed(OutputDevice::DEVICE_BUFFER);
-
-        let (sender, receiver) = async_channel::bounded(OutputDevice::MAX_FRAME_QUEUE_LEN);
-        let mut receiver = FrameReceiver::new(channels, sample_rate, receiver);
-        let stream = Box::new(
-            device
-                .build_output_stream(
-                    &config,
-                    move |data: &mut [f32], _: &cpal::OutputCallbackInfo| match receiver
-                        .fill_buffer(data)
-                    {
-                        Ok(satisfied) => {
-                            if satisfied == 0 {
-                                println!("Dropped output!");
-                            } else if satisfied < data.len() {
-                                println!("Underfull output: {} < {}!", satisfied, data.len());
-                            }
-                        }
-                        Err(FrameReceiverError::EndOfStream) => {
-                            println!("At end of stream.")
-                        }
-                    },
-                    move |_err| {
-                        todo!();
-                    },
-                    None, // blocking (??)
-                )
-                .map_err(OpenError::BuildStreamError)?,
+            .map_err(OpenError::BuildStreamError)?,
         );
         stream.play().or(Err(OpenError::PlayStreamError))?;
 
         Ok(OutputDevice {
-            sender,
+            frames,
+            spent,
+            errors: err_receiver,
+            underruns,
             _stream: stream,
         })
     }
+
+    /// Convert the oldest error reported by the device (if any) to an
+    /// OutputError, or default to DeviceClosed.
+    fn closed_error(&self) -> OutputError {
+        self.errors
+            .try_recv()
+            .map_or(OutputError::DeviceClosed, OutputError::StreamError)
+    }
+}
+
+/// Build an output stream that converts the f32 samples of Frames to samples
+/// of type T (i.e. the device's sample format), counting the callbacks that
+/// couldn't be completely filled in `underruns`
+fn build_stream<T, E>(
+    device: &cpal::Device,
+    config: &cpal::StreamConfig,
+    mut receiver: FrameReceiver,
+    underruns: Arc<AtomicUsize>,
+    on_error: E,
+) -> Result<cpal::Stream, cpal::BuildStreamError>
+where
+    T: SizedSample + FromSample<f32>,
+    E: FnMut(StreamError) + Send + 'static,
+{
+    device.build_output_stream(
+        config,
+        move |data: &mut [T], _: &cpal::OutputCallbackInfo| {
+            let satisfied = match receiver.fill_buffer(data) {
+                Ok(satisfied) => {
+                    if satisfied < data.len() {
+                        underruns.fetch_add(1, Ordering::Relaxed);
+                    }
+                    satisfied
+                }
+                Err(FrameReceiverError::EndOfStream) => 0,
+            };
+            // Output silence for whatever couldn't be filled
+            data[satisfied..].fill(T::EQUILIBRIUM);
+        },
+        on_error,
+        None, // blocking (??)
+    )
 }
 
 impl Output for OutputDevice {
-    fn push(&mut self, frame: Frame) -> Result<(), OutputError> {
-        self.sender
-            .send_blocking(frame)
-            .map_err(|_| OutputError::DeviceClosed)
+    /// Queue the frame to be played, waiting while the queue is full
+    fn push(&mut self, mut frame: Frame) -> Result<(), OutputError> {
+        // (deallocate the buffers that the callback has finished with)
+        while self.spent.pop().is_some() {}
+        loop {
+            if let Ok(e) = self.errors.try_recv() {
+                return Err(OutputError::StreamError(e));
+            }
+            match self.frames.push(frame) {
+                Ok(()) => return Ok(()),
+                Err(_) if self.frames.is_closed() => return Err(self.closed_error()),
+                Err(f) => {
+                    frame = f;
+                    self.frames.wait();
+                }
+            }
+        }
+    }
+
+    /// Wait until all the frames that have been pushed have been taken by
+    /// the device (so that the end of a stream isn't cut off when the device
+    /// is dropped)
+    fn flush(&mut self) -> Result<(), OutputError> {
+        while self.frames.len() > 0 {
+            if let Ok(e) = self.errors.try_recv() {
+                return Err(OutputError::StreamError(e));
+            } else if self.frames.is_closed() {
+                return Err(self.closed_error());
+            }
+            self.frames.wait();
+        }
+        Ok(())
+    }
+
+    fn underruns(&self) -> usize {
+        self.underruns.load(Ordering::Relaxed)
+    }
+
+    fn queue_len(&self) -> usize {
+        self.frames.len()
     }
 }
 
-/// Wraps an async_channel::Receiver<Frame> with logic to copy sample data
-/// from Frames into the buffers that the output device has requested be filled.
+/// Receives Frames from an `OutputDevice`, with logic to copy sample data from
+/// them into the buffers that the output device has requested be filled.
 struct FrameReceiver {
     channels: stream::ChannelCount,
     sample_rate: stream::SampleRate,
-    receiver: Receiver<Frame>,
+    receiver: Arc<Queue<Frame>>,
+    /// (where the samples of each frame go once they've all been copied)
+    spent: Arc<Queue<Vec<f32>>>,
     cur_frame: Option<Frame>,
     cur_sample: Option<usize>, // Some iff samples remain in cur_frame
 }
@@ -140,27 +288,35 @@ impl FrameReceiver {
     fn new(
         channels: stream::ChannelCount,
         sample_rate: stream::SampleRate,
-        receiver: Receiver<Frame>,
+        receiver: Arc<Queue<Frame>>,
+        spent: Arc<Queue<Vec<f32>>>,
     ) -> FrameReceiver {
         FrameReceiver {
             channels,
             sample_rate,
             receiver,
+            spent,
             cur_frame: None,
             cur_sample: None,
         }
     }
 
-    /// Fill the given output buffer with samples.
+    /// Fill the given output buffer with samples (converted to the device's
+    /// sample format).
     /// @return the number of samples returned, which may be less than the
     ///     length of @p buf if insufficient samples are currently queued.
-    fn fill_buffer(&mut self, buf: &mut [f32]) -> Result<usize, FrameReceiverError> {
+    fn fill_buffer<T: FromSample<f32>>(
+        &mut self,
+        buf: &mut [T],
+    ) -> Result<usize, FrameReceiverError> {
         let mut satisfied: usize = 0;
 
         while satisfied < buf.len() {
             match self.next_slice(buf.len() - satisfied) {
                 Ok(Some(slice)) => {
-                    buf[satisfied..satisfied + slice.len()].copy_from_slice(slice);
+                    for (out, s) in buf[satisfied..].iter_mut().zip(slice) {
+                        *out = T::from_sample_(*s);
+                    }
                     satisfied += slice.len();
                 }
                 Ok(None) => return Ok(satisfied),
@@ -180,16 +336,19 @@ impl FrameReceiver {
             Ok(Some(self.next_slice_from_current(cur_sample, max_len)))
         } else {
             // Have returned the entire previous frame; try to get the next
-            match self.receiver.try_recv() {
-                Ok(next) => {
+            match self.receiver.pop() {
+                Some(next) => {
                     assert!(next.channels == self.channels);
                     assert!(next.sample_rate == self.sample_rate);
-                    self.cur_frame = Some(next);
+                    // (so that it isn't deallocated here)
+                    if let Some(previous) = self.cur_frame.replace(next) {
+                        let _full = self.spent.push(previous.samples);
+                    }
                     self.cur_sample = Some(0);
                     Ok(Some(self.next_slice_from_current(0, max_len)))
                 }
-                Err(TryRecvError::Empty) => Ok(None),
-                Err(TryRecvError::Closed) => Err(FrameReceiverError::EndOfStream),
+                None if self.receiver.is_closed() => Err(FrameReceiverError::EndOfStream),
+                None => Ok(None),
             }
         }
     }
@@ -223,17 +382,18 @@ mod tests {
     fn test_recv_next_slice() {
         let channels = stream::ChannelCount(1);
         let sample_rate = stream::SampleRate(2);
-        let (send, recv) = async_channel::unbounded::<Frame>();
-        let mut iter = FrameReceiver::new(channels, sample_rate, recv);
+        let send = Queue::new(4);
+        let mut iter = FrameReceiver::new(channels, sample_rate, send.clone(), Queue::new(4));
         assert!(iter.next_slice(42).unwrap().is_none());
 
         // Send a frame...
         let f1 = Frame {
             channels,
             sample_rate,
+            start_index: 0,
             samples: vec![1., 2., 3., 4.],
         };
-        send.send_blocking(f1).unwrap();
+        send.push(f1).unwrap();
         // Should be able to get just the first 3/4 samples:
         assert_eq!(iter.next_slice(3).unwrap().unwrap(), [1., 2., 3.]);
         // And then just the remaining sample:
@@ -245,9 +405,10 @@ mod tests {
         let f2 = Frame {
             channels,
             sample_rate,
+            start_index: 4,
             samples: vec![5., 6., 7., 8.],
         };
-        send.send_blocking(f2).unwrap();
+        send.push(f2).unwrap();
         // Should be able to get the entire frame:
         assert_eq!(iter.next_slice(42).unwrap().unwrap(), [5., 6., 7., 8.]);
 
@@ -256,9 +417,10 @@ mod tests {
         let f3 = Frame {
             channels,
             sample_rate,
+            start_index: 8,
             samples: vec![9., 10.],
         };
-        send.send_blocking(f3).unwrap();
+        send.push(f3).unwrap();
         assert_eq!(iter.next_slice(42).unwrap().unwrap(), [9., 10.])
     }
 
@@ -266,25 +428,29 @@ mod tests {
     fn test_fill_buf() {
         let channels = stream::ChannelCount(1);
         let sample_rate = stream::SampleRate(2);
-        let (send, recv) = async_channel::unbounded::<Frame>();
-        let mut iter = FrameReceiver::new(channels, sample_rate, recv);
+        let send = Queue::new(4);
+        let spent = Queue::new(4);
+        let mut iter = FrameReceiver::new(channels, sample_rate, send.clone(), spent.clone());
 
         // Send a few frames...
-        send.send_blocking(Frame {
+        send.push(Frame {
             channels,
             sample_rate,
+            start_index: 0,
             samples: vec![1., 2.],
         })
         .unwrap();
-        send.send_blocking(Frame {
+        send.push(Frame {
             channels,
             sample_rate,
+            start_index: 2,
             samples: vec![3., 4.],
         })
         .unwrap();
-        send.send_blocking(Frame {
+        send.push(Frame {
             channels,
             sample_rate,
+            start_index: 4,
             samples: vec![5., 6.],
         })
         .unwrap();
@@ -298,5 +464,7 @@ mod tests {
         let mut buf = [0f32; 2];
         assert_eq!(iter.fill_buffer(&mut buf[..]).unwrap(), 1);
         assert_eq!(buf, [6., 0.]);
+        // The first 2 frames have been passed back:
+        assert_eq!(spent.len(), 2);
     }
 }
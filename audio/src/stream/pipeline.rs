@@ -1,6 +1,9 @@
-use super::input::{Input, InputError};
-use super::output::{Output, OutputError};
-use super::Frame;
+use std::any::Any;
+use std::iter;
+
+use super::input::{Input, InputDeviceError, InputError};
+use super::output::{OpenError, Output, OutputError};
+use super::{ChannelCount, Frame};
 
 /// A processing step that transforms an input into an output
 pub trait Step {
@@ -15,6 +18,41 @@ pub trait Step {
 
     /// Get the next output of this step, if available.
     fn pop_output(&mut self) -> Option<Self::Output>;
+
+    /// Process a block of inputs, appending all the resulting outputs to
+    /// `output`.
+    /// This is equivalent to pushing each input and popping outputs until
+    /// there are none, which is what the default implementation does; steps
+    /// can implement it directly to avoid the per-item overhead.
+    fn process_block<It>(&mut self, input: It, output: &mut Vec<Self::Output>)
+    where
+        It: IntoIterator<Item = Self::Input>,
+        Self: Sized,
+    {
+        for i in input {
+            self.push_input(i);
+            while let Some(o) = self.pop_output() {
+                output.push(o);
+            }
+        }
+    }
+
+    /// Tell the step the sample index of its next input, e.g. for a
+    /// per-sample step, the start of the next frame (see `PerChannel`), so
+    /// that it can schedule changes by sample (see `synth::Param`).
+    /// The default implementation ignores it.
+    fn seek(&mut self, _index: usize) {}
+
+    /// Append any outputs for input that's being held back (e.g. the tail of
+    /// a filter, waiting for the input after it) to `output`, at the end of
+    /// the input.
+    /// The default implementation does nothing, for steps that don't hold any
+    /// back.
+    fn flush(&mut self, _output: &mut Vec<Self::Output>)
+    where
+        Self: Sized,
+    {
+    }
 }
 
 /// Encapsulates some audio input, a processing step to transform that input,
@@ -24,12 +62,20 @@ pub struct Pipeline<I: Input, S: Step<Input = I::Item, Output = Frame>, O: Outpu
     input: I,
     step: S,
     output: O,
+    // (buffers for process_block)
+    inputs: Vec<I::Item>,
+    outputs: Vec<Frame>,
 }
 
 #[derive(Debug)]
 pub enum ProcessError {
     InputError(InputError),
     OutputError(OutputError),
+    /// The input device couldn't be opened (e.g. when setting up a
+    /// `PipelineExecutor`)
+    InputDeviceError(InputDeviceError),
+    /// The output device couldn't be opened
+    OpenError(OpenError),
 }
 
 impl<I: Input, S: Step<Input = I::Item, Output = Frame>, O: Output> Pipeline<I, S, O> {
@@ -38,6 +84,8 @@ impl<I: Input, S: Step<Input = I::Item, Output = Frame>, O: Output> Pipeline<I,
             input,
             step,
             output,
+            inputs: Vec::new(),
+            outputs: Vec::new(),
         }
     }
 
@@ -56,6 +104,54 @@ impl<I: Input, S: Step<Input = I::Item, Output = Frame>, O: Output> Pipeline<I,
         }
     }
 
+    /// Like `process_once`, but after waiting for the next input, reads up to
+    /// `max_len` inputs that are available without waiting (stopping at the
+    /// first error), and processes them as a block
+    pub fn process_block(&mut self, max_len: usize) -> Result<(), ProcessError> {
+        let mut error = None;
+        match self.input.read() {
+            Ok(input) => self.inputs.push(input),
+            Err(e) => return Err(ProcessError::InputError(e)),
+        }
+        while self.inputs.len() < max_len {
+            match self.input.try_read() {
+                Ok(Some(input)) => self.inputs.push(input),
+                Ok(None) => break,
+                Err(e) => {
+                    error = Some(e);
+                    break;
+                }
+            }
+        }
+        self.step
+            .process_block(self.inputs.drain(..), &mut self.outputs);
+        for output in self.outputs.drain(..) {
+            self.output
+                .push(output)
+                .map_err(ProcessError::OutputError)?;
+        }
+        match error {
+            Some(e) => Err(ProcessError::InputError(e)),
+            None => Ok(()),
+        }
+    }
+
+    /// Push anything that the step is holding back (see `Step::flush`) to
+    /// the output, at the end of the input
+    pub fn flush_step(&mut self) -> Result<(), ProcessError> {
+        self.step.flush(&mut self.outputs);
+        for output in self.outputs.drain(..) {
+            self.output
+                .push(output)
+                .map_err(ProcessError::OutputError)?;
+        }
+        Ok(())
+    }
+
+    pub fn input(&self) -> &I {
+        &self.input
+    }
+
     pub fn input_mut(&mut self) -> &mut I {
         &mut self.input
     }
@@ -63,6 +159,14 @@ impl<I: Input, S: Step<Input = I::Item, Output = Frame>, O: Output> Pipeline<I,
     pub fn step_mut(&mut self) -> &mut S {
         &mut self.step
     }
+
+    pub fn output(&self) -> &O {
+        &self.output
+    }
+
+    pub fn output_mut(&mut self) -> &mut O {
+        &mut self.output
+    }
 }
 
 /// A `Step` that outputs its input.
@@ -90,6 +194,11 @@ impl<T> Step for Identity<T> {
     fn pop_output(&mut self) -> Option<T> {
         self.next.take()
     }
+
+    fn process_block<It: IntoIterator<Item = T>>(&mut self, input: It, output: &mut Vec<T>) {
+        assert!(self.next.is_none());
+        output.extend(input);
+    }
 }
 
 /// Connects the output of one `Step` to the input of another.
@@ -97,6 +206,8 @@ impl<T> Step for Identity<T> {
 pub struct Chain<First: Step, Second: Step<Input = First::Output>> {
     first: First,
     second: Second,
+    // (the outputs of first, for process_block)
+    intermediate: Vec<First::Output>,
 }
 
 impl<First, Second> Chain<First, Second>
@@ -105,7 +216,11 @@ where
     Second: Step<Input = First::Output>,
 {
     pub fn new(first: First, second: Second) -> Chain<First, Second> {
-        Chain { first, second }
+        Chain {
+            first,
+            second,
+            intermediate: Vec::new(),
+        }
     }
 
     pub fn first_mut(&mut self) -> &mut First {
@@ -138,4 +253,392 @@ impl<First: Step, Second: Step<Input = First::Output>> Step for Chain<First, Sec
             None
         }
     }
+
+    fn process_block<It>(&mut self, input: It, output: &mut Vec<Self::Output>)
+    where
+        It: IntoIterator<Item = Self::Input>,
+    {
+        self.first.process_block(input, &mut self.intermediate);
+        self.second
+            .process_block(self.intermediate.drain(..), output);
+    }
+
+    fn flush(&mut self, output: &mut Vec<Self::Output>) {
+        self.first.flush(&mut self.intermediate);
+        self.second
+            .process_block(self.intermediate.drain(..), output);
+        self.second.flush(output);
+    }
+}
+
+/// Applies a per-sample `Step` to each channel of interlaced `Frame`s, using
+/// an independent instance of the step for each channel.
+/// The wrapped steps must output exactly one sample for each input sample
+/// (e.g. `Gain` or `LTI`).
+pub struct PerChannel<S: Step<Input = f32, Output = f32>> {
+    steps: Vec<S>,
+    // (the output of a step for one channel of a frame)
+    channel: Vec<f32>,
+    next: Option<Frame>,
+}
+
+impl<S: Step<Input = f32, Output = f32>> PerChannel<S> {
+    /// Creates a step for each of `channels` by calling `factory`
+    pub fn new<F: FnMut() -> S>(channels: ChannelCount, factory: F) -> PerChannel<S> {
+        PerChannel {
+            steps: iter::repeat_with(factory)
+                .take(usize::from(channels))
+                .collect(),
+            channel: Vec::new(),
+            next: None,
+        }
+    }
+
+    /// The step for each channel
+    pub fn steps_mut(&mut self) -> &mut [S] {
+        &mut self.steps
+    }
+
+    fn process_frame(&mut self, frame: &mut Frame) {
+        let channels = self.steps.len();
+        assert_eq!(usize::from(frame.channels), channels);
+        for (ch, step) in self.steps.iter_mut().enumerate() {
+            step.seek(frame.start_index);
+            self.channel.clear();
+            let samples = frame.samples.iter().skip(ch).step_by(channels);
+            step.process_block(samples.copied(), &mut self.channel);
+            assert_eq!(self.channel.len(), frame.samples.len() / channels);
+            let samples = frame.samples.iter_mut().skip(ch).step_by(channels);
+            for (sample, s) in samples.zip(&self.channel) {
+                *sample = *s;
+            }
+        }
+    }
+}
+
+impl<S: Step<Input = f32, Output = f32>> Step for PerChannel<S> {
+    type Input = Frame;
+    type Output = Frame;
+
+    fn push_input(&mut self, mut frame: Frame) {
+        assert!(self.next.is_none());
+        self.process_frame(&mut frame);
+        self.next = Some(frame);
+    }
+
+    fn pop_output(&mut self) -> Option<Frame> {
+        self.next.take()
+    }
+
+    fn process_block<It: IntoIterator<Item = Frame>>(
+        &mut self,
+        input: It,
+        output: &mut Vec<Frame>,
+    ) {
+        assert!(self.next.is_none());
+        for mut frame in input {
+            self.process_frame(&mut frame);
+            output.push(frame);
+        }
+    }
+}
+
+/// The tail of a pipeline: a `Step` and the `Output` that sinks its results.
+/// This is itself an `Output`, so can be used as one of the branches of a
+/// `Tee`.
+pub struct Branch<S: Step, O: Output<S::Output>> {
+    step: S,
+    output: O,
+    // (the outputs of step, waiting to be pushed)
+    pending: Vec<S::Output>,
+}
+
+impl<S: Step, O: Output<S::Output>> Branch<S, O> {
+    pub fn new(step: S, output: O) -> Branch<S, O> {
+        Branch {
+            step,
+            output,
+            pending: Vec::new(),
+        }
+    }
+
+    pub fn step_mut(&mut self) -> &mut S {
+        &mut self.step
+    }
+
+    pub fn output_mut(&mut self) -> &mut O {
+        &mut self.output
+    }
+}
+
+impl<S: Step, O: Output<S::Output>> Output<S::Input> for Branch<S, O> {
+    fn push(&mut self, item: S::Input) -> Result<(), OutputError> {
+        self.step.process_block(iter::once(item), &mut self.pending);
+        for output in self.pending.drain(..) {
+            self.output.push(output)?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), OutputError> {
+        self.output.flush()
+    }
+
+    fn underruns(&self) -> usize {
+        self.output.underruns()
+    }
+
+    fn queue_len(&self) -> usize {
+        self.output.queue_len()
+    }
+}
+
+/// An `Output` that can be downcast to its concrete type, so that the
+/// branches of a `Tee` can be accessed
+trait AnyOutput<T>: Output<T> + Send {
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+}
+
+impl<T, O: Output<T> + Send + 'static> AnyOutput<T> for O {
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+/// Identifies one of the branches of a `Tee`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct BranchId(usize);
+
+/// An `Output` that copies each item to any number of branches (e.g. a
+/// recorder, an FFT, and level meters), each generally a `Branch` with its own
+/// processing and sink.
+/// A branch that fails is removed, without affecting the others.
+pub struct Tee<T: Clone> {
+    branches: Vec<(BranchId, Box<dyn AnyOutput<T>>)>,
+    next_id: usize,
+}
+
+impl<T: Clone> Default for Tee<T> {
+    fn default() -> Tee<T> {
+        Tee {
+            branches: Vec::new(),
+            next_id: 0,
+        }
+    }
+}
+
+impl<T: Clone> Tee<T> {
+    pub fn add<O: Output<T> + Send + 'static>(&mut self, branch: O) -> BranchId {
+        let id = BranchId(self.next_id);
+        self.next_id += 1;
+        self.branches.push((id, Box::new(branch)));
+        id
+    }
+
+    /// Remove a branch, returning it (or None if it has already been removed,
+    /// including if it failed)
+    pub fn remove(&mut self, id: BranchId) -> Option<Box<dyn Output<T> + Send>> {
+        let index = self.branches.iter().position(|(i, _)| *i == id)?;
+        Some(self.branches.remove(index).1)
+    }
+
+    /// Get a branch, if it is still present and has the type `O`
+    pub fn branch_mut<O: Output<T> + Send + 'static>(&mut self, id: BranchId) -> Option<&mut O> {
+        let (_, branch) = self.branches.iter_mut().find(|(i, _)| *i == id)?;
+        branch.as_mut().as_any_mut().downcast_mut()
+    }
+
+    pub fn contains(&self, id: BranchId) -> bool {
+        self.branches.iter().any(|(i, _)| *i == id)
+    }
+
+    pub fn len(&self) -> usize {
+        self.branches.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.branches.is_empty()
+    }
+}
+
+impl<T: Clone> Output<T> for Tee<T> {
+    /// Push the item to every branch, returning `BranchesFailed` with the
+    /// branches that failed (and have been removed), if any.
+    fn push(&mut self, item: T) -> Result<(), OutputError> {
+        let mut failures = Vec::new();
+        let last = self.branches.len().saturating_sub(1);
+        let mut item = Some(item);
+        let mut index = 0;
+        self.branches.retain_mut(|(id, branch)| {
+            // (the last branch can have the original)
+            let item = if index == last {
+                item.take().unwrap()
+            } else {
+                item.clone().unwrap()
+            };
+            index += 1;
+            match branch.push(item) {
+                Ok(()) => true,
+                Err(e) => {
+                    failures.push((*id, e));
+                    false
+                }
+            }
+        });
+        if failures.is_empty() {
+            Ok(())
+        } else {
+            Err(OutputError::BranchesFailed(failures))
+        }
+    }
+
+    /// Flush every branch, removing (and returning) those that fail, as for
+    /// `push`
+    fn flush(&mut self) -> Result<(), OutputError> {
+        let mut failures = Vec::new();
+        self.branches
+            .retain_mut(|(id, branch)| match branch.flush() {
+                Ok(()) => true,
+                Err(e) => {
+                    failures.push((*id, e));
+                    false
+                }
+            });
+        if failures.is_empty() {
+            Ok(())
+        } else {
+            Err(OutputError::BranchesFailed(failures))
+        }
+    }
+
+    /// The total of all the branches' underruns
+    fn underruns(&self) -> usize {
+        self.branches.iter().map(|(_, b)| b.underruns()).sum()
+    }
+
+    /// The longest of the branches' queues
+    fn queue_len(&self) -> usize {
+        self.branches
+            .iter()
+            .map(|(_, b)| b.queue_len())
+            .max()
+            .unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use async_channel::Sender;
+
+    use crate::dsp::filter::LTI;
+    use crate::dsp::Decibels;
+    use crate::stream::buffer::FrameAccumulator;
+    use crate::stream::routing::ChannelMatrix;
+    use crate::stream::SampleRate;
+    use crate::synth::Gain;
+
+    #[test]
+    fn per_channel_state() {
+        // A running sum of the inputs, which should be independent per channel
+        let mut step = PerChannel::new(ChannelCount::new(2), || LTI::new(vec![1., -1.], vec![1.]));
+        let frames = [
+            (0, [1., 10., 2., 20.], [1., 10., 3., 30.]),
+            (2, [3., 30., 4., 40.], [6., 60., 10., 100.]),
+        ];
+        for (start_index, samples, expect) in frames {
+            step.push_input(Frame {
+                channels: ChannelCount::new(2),
+                sample_rate: SampleRate::new(44100),
+                start_index,
+                samples: samples.to_vec(),
+            });
+            let out = step.pop_output().unwrap();
+            assert_eq!(out.start_index, start_index);
+            assert_eq!(out.samples, expect);
+            assert!(step.pop_output().is_none());
+        }
+    }
+
+    #[test]
+    fn block_matches_per_item() {
+        let make_step = || {
+            let channels = ChannelCount::new(2);
+            Chain::new(
+                LTI::new(vec![1., -0.5], vec![0.5, 0.5]),
+                Chain::new(
+                    Gain::new(Decibels::new(-6.)),
+                    Chain::new(
+                        FrameAccumulator::new(channels, SampleRate::new(44100), 6),
+                        PerChannel::new(channels, || LTI::new(vec![1., -1.], vec![1.])),
+                    ),
+                ),
+            )
+        };
+        let input: Vec<f32> = (0..100).map(|i| (i as f32).sin()).collect();
+
+        let mut step = make_step();
+        let mut expect = Vec::new();
+        for i in &input {
+            step.push_input(*i);
+            while let Some(frame) = step.pop_output() {
+                expect.push(frame);
+            }
+        }
+
+        let mut step = make_step();
+        let mut output = Vec::new();
+        for block in input.chunks(7) {
+            step.process_block(block.iter().copied(), &mut output);
+        }
+
+        assert_eq!(output.len(), expect.len());
+        for (o, e) in output.iter().zip(&expect) {
+            assert_eq!(o.start_index, e.start_index);
+            assert_eq!(o.samples, e.samples);
+        }
+    }
+
+    #[test]
+    fn tee_branch_failure() {
+        let frame = Frame {
+            channels: ChannelCount::new(2),
+            sample_rate: SampleRate::new(44100),
+            start_index: 0,
+            samples: vec![1., 2., 3., 4.],
+        };
+        let (copy_send, copy_recv) = async_channel::unbounded();
+        let (mono_send, mono_recv) = async_channel::unbounded();
+        let mut tee = Tee::default();
+        let copy = tee.add(copy_send);
+        let mono = tee.add(Branch::new(
+            ChannelMatrix::select(ChannelCount::new(2), &[1]),
+            mono_send,
+        ));
+
+        tee.push(frame.clone()).unwrap();
+        assert_eq!(copy_recv.try_recv().unwrap().samples, [1., 2., 3., 4.]);
+        assert_eq!(mono_recv.try_recv().unwrap().samples, [2., 4.]);
+        let branch = tee.branch_mut::<Branch<ChannelMatrix, Sender<Frame>>>(mono);
+        assert_eq!(branch.unwrap().step_mut().outputs(), ChannelCount::new(1));
+        assert!(tee.branch_mut::<Sender<Frame>>(mono).is_none());
+
+        // The failure of one branch shouldn't affect the other:
+        drop(copy_recv);
+        match tee.push(frame.clone()) {
+            Err(OutputError::BranchesFailed(failures)) => {
+                assert_eq!(failures.len(), 1);
+                assert_eq!(failures[0].0, copy);
+                assert!(matches!(failures[0].1, OutputError::Disconnected));
+            }
+            r => panic!("unexpected {:?}", r),
+        }
+        assert_eq!(mono_recv.try_recv().unwrap().samples, [2., 4.]);
+        assert!(!tee.contains(copy));
+
+        assert!(tee.remove(mono).is_some());
+        assert!(tee.is_empty());
+        tee.push(frame).unwrap();
+    }
 }
@@ -0,0 +1,119 @@
+use std::sync::Arc;
+use std::thread::{self, Thread};
+use std::time::Duration;
+
+use concurrent_queue::{ConcurrentQueue, PopError, PushError};
+
+/// A bounded queue between a realtime audio callback and the thread that owns
+/// the device (i.e. the thread that opened it, since devices can't be moved
+/// between threads).
+/// Pushing and popping never allocate, lock or block, so they can be done in
+/// the callback; the owner thread can `wait` until the callback has pushed or
+/// popped something.
+pub(crate) struct Queue<T> {
+    items: ConcurrentQueue<T>,
+    owner: Thread,
+}
+
+impl<T> Queue<T> {
+    /// The longest that `wait` waits for, in case a wake-up is missed (e.g.
+    /// because the device has stopped calling back)
+    const MAX_WAIT: Duration = Duration::from_millis(50);
+
+    /// Create a queue owned by the current thread
+    pub fn new(capacity: usize) -> Arc<Queue<T>> {
+        Arc::new(Queue {
+            items: ConcurrentQueue::bounded(capacity),
+            owner: thread::current(),
+        })
+    }
+
+    /// Add an item, or return it if the queue is full (or closed)
+    pub fn push(&self, item: T) -> Result<(), T> {
+        match self.items.push(item) {
+            Ok(()) => {
+                self.owner.unpark();
+                Ok(())
+            }
+            Err(PushError::Full(item) | PushError::Closed(item)) => Err(item),
+        }
+    }
+
+    /// The oldest item, if there is one. Items that were pushed before the
+    /// queue was closed can still be popped.
+    pub fn pop(&self) -> Option<T> {
+        match self.items.pop() {
+            Ok(item) => {
+                self.owner.unpark();
+                Some(item)
+            }
+            Err(PopError::Empty | PopError::Closed) => None,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    /// Stop any more items being pushed (e.g. because the device has gone
+    /// away)
+    pub fn close(&self) {
+        self.items.close();
+        self.owner.unpark();
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.items.is_closed()
+    }
+
+    /// Wait (on the owner thread) until an item is pushed or popped on
+    /// another thread. This may also return early, so the caller should
+    /// check whatever it is waiting for again.
+    pub fn wait(&self) {
+        debug_assert_eq!(thread::current().id(), self.owner.id());
+        thread::park_timeout(Self::MAX_WAIT);
+    }
+}
+
+/// Create a queue of buffers for a callback to fill (or that a callback has
+/// finished with), with room for `count` buffers, and that many buffers of
+/// the given capacity already in it. The buffers are allocated here, so that
+/// the callback doesn't have to.
+pub(crate) fn buffer_pool(count: usize, capacity: usize) -> Arc<Queue<Vec<f32>>> {
+    let pool = Queue::new(count);
+    for _ in 0..count {
+        let _full = pool.push(Vec::with_capacity(capacity));
+    }
+    pool
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wait_for_other_thread() {
+        let queue = Queue::new(2);
+        let producer = queue.clone();
+        let join = thread::spawn(move || {
+            for i in 0..4 {
+                while producer.push(i).is_err() {
+                    thread::yield_now();
+                }
+            }
+            producer.close();
+        });
+
+        let mut received = Vec::new();
+        while received.len() < 4 {
+            match queue.pop() {
+                Some(i) => received.push(i),
+                None => queue.wait(),
+            }
+        }
+        join.join().unwrap();
+        assert_eq!(received, [0, 1, 2, 3]);
+        assert!(queue.is_closed());
+        assert_eq!(queue.push(4), Err(4));
+    }
+}
@@ -0,0 +1,357 @@
+use std::f64::consts::PI;
+
+use crate::stream::pipeline::Step;
+use crate::stream::{ChannelCount, Frame, SampleRate};
+
+/// Converts a stream of `Frame`s from one sample rate to another, by
+/// windowed-sinc interpolation (using a polyphase table of filter
+/// coefficients, interpolated between phases for arbitrary rate ratios).
+///
+/// Output sample k is aligned with time k / output rate of the input, i.e.
+/// the output isn't delayed, although each output frame is only produced once
+/// enough input has been received to compute it.
+pub struct Resampler {
+    channels: ChannelCount,
+    input_rate: SampleRate,
+    output_rate: SampleRate,
+    /// The number of input samples (before and after) that each output sample
+    /// is interpolated from
+    half_taps: usize,
+    /// For each of `PHASES + 1` fractional offsets, `2 * half_taps` filter
+    /// coefficients
+    table: Vec<Vec<f32>>,
+    /// The ratio of output / input rates, reduced to lowest terms (up, down)
+    up: u64,
+    down: u64,
+    /// Per-channel input history. `history[ch][0]` is input sample index
+    /// `history_start` (which is negative until enough input is discarded).
+    history: Vec<Vec<f32>>,
+    history_start: i64,
+    next_input_index: usize,
+    next_output_index: u64,
+    output: Option<Frame>,
+}
+
+impl Resampler {
+    /// The number of fractional offsets between input samples that filter
+    /// coefficients are precomputed for
+    const PHASES: usize = 256;
+
+    /// The number of input samples on each side of an output sample that are
+    /// interpolated from, when upsampling (and proportionally more when
+    /// downsampling, to keep the same transition band relative to the output
+    /// rate)
+    const HALF_TAPS: usize = 64;
+
+    /// The fraction of the (lower) nyquist frequency to pass; the remainder
+    /// is the filter's transition band
+    const ROLLOFF: f64 = 0.95;
+
+    pub fn new(channels: ChannelCount, input_rate: SampleRate, output_rate: SampleRate) -> Self {
+        let (input, output) = (u32::from(input_rate) as u64, u32::from(output_rate) as u64);
+        let divisor = gcd(input, output);
+        let (up, down) = (output / divisor, input / divisor);
+
+        // When downsampling, the cutoff must be below the output nyquist
+        // frequency, and the filter correspondingly longer
+        let scale = (output as f64 / input as f64).min(1.0);
+        let cutoff = 0.5 * scale * Resampler::ROLLOFF; // (cycles per input sample)
+        let half_taps = (Resampler::HALF_TAPS as f64 / scale).ceil() as usize;
+
+        let table = (0..=Resampler::PHASES)
+            .map(|phase| {
+                let frac = phase as f64 / Resampler::PHASES as f64;
+                (0..2 * half_taps)
+                    .map(|j| {
+                        // Distance from the output sample to this input
+                        let x = j as f64 - (half_taps - 1) as f64 - frac;
+                        (2.0 * cutoff * sinc(2.0 * cutoff * x) * window(x, half_taps as f64)) as f32
+                    })
+                    .collect()
+            })
+            .collect();
+
+        let mut resampler = Resampler {
+            channels,
+            input_rate,
+            output_rate,
+            half_taps,
+            table,
+            up,
+            down,
+            history: vec![Vec::new(); usize::from(channels)],
+            history_start: 0,
+            next_input_index: 0,
+            next_output_index: 0,
+            output: None,
+        };
+        resampler.restart(0);
+        resampler
+    }
+
+    pub fn input_rate(&self) -> SampleRate {
+        self.input_rate
+    }
+
+    pub fn output_rate(&self) -> SampleRate {
+        self.output_rate
+    }
+
+    /// Start again from input sample `index`, as if it were the start of the
+    /// stream (so the inputs before it are silence)
+    fn restart(&mut self, index: usize) {
+        for history in &mut self.history {
+            history.clear();
+            history.resize(self.half_taps - 1, 0.);
+        }
+        self.history_start = index as i64 - (self.half_taps as i64 - 1);
+        self.next_input_index = index;
+        // (the first output sample at or after the input sample)
+        self.next_output_index = (index as u64 * self.up).div_ceil(self.down);
+    }
+
+    /// Append a frame's samples to the per-channel history
+    fn push_history(&mut self, frame: &Frame) {
+        let channels = usize::from(self.channels);
+        // Missing input (see `SampleBuffer::push`) is silence, and input
+        // whose indices went backwards (e.g. because it was reopened) starts
+        // again, discarding the end of the previous input that hadn't been
+        // output yet:
+        let gap = match frame.start_index.checked_sub(self.next_input_index) {
+            Some(gap) => gap,
+            None => {
+                self.restart(frame.start_index);
+                0
+            }
+        };
+        for ch in 0..channels {
+            let history = &mut self.history[ch];
+            history.resize(history.len() + gap, 0.);
+            history.extend(frame.samples.iter().skip(ch).step_by(channels));
+        }
+        self.next_input_index = frame.end_index();
+    }
+
+    /// Compute the output samples that the history has the inputs for, up to
+    /// (but not including) `end`, an input position multiplied by `up`
+    fn resample(&mut self, end: u64) {
+        let channels = usize::from(self.channels);
+        let start_index = self.next_output_index as usize;
+        let mut samples = Vec::new();
+        let available = self.history_start + self.history[0].len() as i64;
+        loop {
+            // The position of the next output sample, in input samples, as
+            // an integer and fractional part:
+            let position = self.next_output_index * self.down;
+            let whole = (position / self.up) as i64;
+            let frac = (position % self.up) as f64 / self.up as f64;
+            // ... which needs inputs up to half_taps after it:
+            if position >= end || whole + self.half_taps as i64 >= available {
+                break;
+            }
+
+            let start = (whole - (self.half_taps as i64 - 1) - self.history_start) as usize;
+            let phase = frac * Resampler::PHASES as f64;
+            let alpha = phase.fract() as f32;
+            for ch in 0..channels {
+                samples.push(self.interpolate(ch, start, phase as usize, alpha));
+            }
+            self.next_output_index += 1;
+        }
+
+        // Discard history that no subsequent output needs:
+        let next_whole = (self.next_output_index * self.down / self.up) as i64;
+        let discard = (next_whole - (self.half_taps as i64 - 1) - self.history_start).max(0);
+        for history in &mut self.history {
+            history.drain(..discard as usize);
+        }
+        self.history_start += discard;
+
+        if !samples.is_empty() {
+            self.output = Some(Frame {
+                channels: self.channels,
+                sample_rate: self.output_rate,
+                start_index,
+                samples,
+            });
+        }
+    }
+
+    /// Compute an output sample from the filter taps for an output position
+    fn interpolate(&self, ch: usize, start: usize, phase: usize, alpha: f32) -> f32 {
+        let history = &self.history[ch][start..start + 2 * self.half_taps];
+        let (h0, h1) = (&self.table[phase], &self.table[phase + 1]);
+        let mut sum = 0.;
+        for j in 0..history.len() {
+            sum += history[j] * (h0[j] + alpha * (h1[j] - h0[j]));
+        }
+        sum
+    }
+}
+
+impl Step for Resampler {
+    type Input = Frame;
+    type Output = Frame;
+
+    fn push_input(&mut self, frame: Frame) {
+        assert!(frame.channels == self.channels);
+        assert!(frame.sample_rate == self.input_rate);
+        assert!(self.output.is_none());
+        self.push_history(&frame);
+        self.resample(u64::MAX);
+    }
+
+    fn pop_output(&mut self) -> Option<Frame> {
+        self.output.take()
+    }
+
+    /// Output the rest of the input, which the filter would otherwise wait
+    /// for the following half_taps of input for, as if it were followed by
+    /// silence
+    fn flush(&mut self, output: &mut Vec<Frame>) {
+        assert!(self.output.is_none());
+        let end = self.next_input_index;
+        for history in &mut self.history {
+            history.resize(history.len() + self.half_taps, 0.);
+        }
+        self.resample(end as u64 * self.up);
+        output.extend(self.output.take());
+        self.restart(end);
+    }
+}
+
+fn gcd(a: u64, b: u64) -> u64 {
+    if b == 0 {
+        a
+    } else {
+        gcd(b, a % b)
+    }
+}
+
+fn sinc(x: f64) -> f64 {
+    if x == 0.0 {
+        1.0
+    } else {
+        (PI * x).sin() / (PI * x)
+    }
+}
+
+/// A (4-term) Blackman-Harris window, centered on 0 and half_width wide on
+/// each side
+fn window(x: f64, half_width: f64) -> f64 {
+    if x.abs() >= half_width {
+        return 0.0;
+    }
+    let t = PI * (x / half_width + 1.0); // (0..2*PI over the window)
+    0.35875 - 0.48829 * t.cos() + 0.14128 * (2.0 * t).cos() - 0.01168 * (3.0 * t).cos()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::stream::buffer::FrameAccumulator;
+
+    /// A sinusoid, computed in f64 to avoid the phase error that `SinIterator`
+    /// accumulates (which would dominate the resampling error)
+    fn sin(sample_rate: SampleRate, frequency: f64) -> impl Iterator<Item = f32> {
+        let rate = u32::from(sample_rate) as f64;
+        (0..).map(move |i| (2. * PI * frequency * i as f64 / rate).sin() as f32)
+    }
+
+    /// Resample a sinusoid, and compare it to one generated at the output rate
+    fn assert_resampled_sin(input_rate: u32, output_rate: u32, frequency: f64) {
+        let channels = ChannelCount::new(1);
+        let (input_rate, output_rate) = (SampleRate::new(input_rate), SampleRate::new(output_rate));
+        let mut accum = FrameAccumulator::new(channels, input_rate, 100);
+        let mut resampler = Resampler::new(channels, input_rate, output_rate);
+
+        let mut output = Vec::new();
+        for s in sin(input_rate, frequency).take(10000) {
+            accum.push_input(s);
+            if let Some(frame) = accum.pop_output() {
+                resampler.push_input(frame);
+                if let Some(out) = resampler.pop_output() {
+                    assert_eq!(out.sample_rate, output_rate);
+                    assert_eq!(out.start_index, output.len());
+                    output.extend(out.samples);
+                }
+            }
+        }
+
+        // Everything except the last half_taps of input should be output:
+        let expect_len = (10000 - resampler.half_taps) * u32::from(output_rate) as usize
+            / u32::from(input_rate) as usize;
+        assert!(output.len().abs_diff(expect_len) <= 1);
+
+        // And (ignoring the start, where the filter sees the silence before the
+        // stream started) should be the same sinusoid:
+        let expected = sin(output_rate, frequency);
+        for (i, (y, expect)) in output.iter().zip(expected).enumerate().skip(200) {
+            assert!((y - expect).abs() < 1e-3, "{}: {} != {}", i, y, expect);
+        }
+
+        // Flushing outputs the rest (as if the input were followed by silence):
+        let mut rest = Vec::new();
+        resampler.flush(&mut rest);
+        assert_eq!(rest.len(), 1);
+        assert_eq!(rest[0].start_index, output.len());
+        output.extend(rest.remove(0).samples);
+        let expect_len =
+            (10000 * u32::from(output_rate) as usize).div_ceil(u32::from(input_rate) as usize);
+        assert_eq!(output.len(), expect_len);
+    }
+
+    #[test]
+    fn downsample() {
+        assert_resampled_sin(48000, 44100, 1000.);
+    }
+
+    #[test]
+    fn upsample() {
+        assert_resampled_sin(22050, 48000, 3000.);
+    }
+
+    #[test]
+    fn multichannel() {
+        let channels = ChannelCount::new(2);
+        let mut resampler = Resampler::new(channels, SampleRate::new(2), SampleRate::new(4));
+        // Upsampling DC in one channel, silence in the other:
+        let mut output = Vec::new();
+        for i in 0..10 {
+            resampler.push_input(Frame {
+                channels,
+                sample_rate: SampleRate::new(2),
+                start_index: i * 50,
+                samples: [1., 0.].repeat(50),
+            });
+            if let Some(f) = resampler.pop_output() {
+                output.extend(f.samples);
+            }
+        }
+        for (i, y) in output.chunks(2).enumerate().skip(200).take(500) {
+            assert!((y[0] - 1.).abs() < 1e-3, "{}: {}", i, y[0]);
+            assert!(y[1].abs() < 1e-6, "{}: {}", i, y[1]);
+        }
+    }
+
+    #[test]
+    fn restart() {
+        let channels = ChannelCount::new(1);
+        let mut resampler = Resampler::new(channels, SampleRate::new(2), SampleRate::new(4));
+        let frame = |start_index| Frame {
+            channels,
+            sample_rate: SampleRate::new(2),
+            start_index,
+            samples: vec![1.; 200],
+        };
+        resampler.push_input(frame(0));
+        assert_eq!(resampler.pop_output().map(|f| f.start_index), Some(0));
+        // The input's indices restart (e.g. it was reopened), so the output's
+        // do too:
+        resampler.push_input(frame(0));
+        let f = resampler.pop_output().unwrap();
+        assert_eq!(f.start_index, 0);
+        assert_eq!(f.samples.len(), 2 * (200 - resampler.half_taps));
+    }
+}
@@ -0,0 +1,127 @@
+use super::input::{ChannelCount, Frame};
+use super::pipeline::Step;
+
+/// A `Step` that changes the channel layout of `Frame`s, by mixing each output
+/// channel from the input channels according to a matrix of gains.
+/// i.e. `output[o] = sum(matrix[o][i] * input[i])`, for each sample.
+pub struct ChannelMatrix {
+    inputs: ChannelCount,
+    outputs: ChannelCount,
+    /// One row of gains (one per input channel) per output channel
+    matrix: Vec<Vec<f32>>,
+    next: Option<Frame>,
+}
+
+impl ChannelMatrix {
+    /// A general routing matrix, with one row per output channel, each
+    /// containing a gain per input channel
+    pub fn new(inputs: ChannelCount, matrix: Vec<Vec<f32>>) -> ChannelMatrix {
+        assert!(!matrix.is_empty());
+        assert!(matrix.iter().all(|row| row.len() == usize::from(inputs)));
+        ChannelMatrix {
+            inputs,
+            outputs: ChannelCount::new(matrix.len() as u16),
+            matrix,
+            next: None,
+        }
+    }
+
+    /// Output a subset (or reordering) of the input channels
+    pub fn select(inputs: ChannelCount, channels: &[usize]) -> ChannelMatrix {
+        let matrix = channels
+            .iter()
+            .map(|&c| {
+                assert!(c < usize::from(inputs));
+                let mut row = vec![0.; usize::from(inputs)];
+                row[c] = 1.;
+                row
+            })
+            .collect();
+        ChannelMatrix::new(inputs, matrix)
+    }
+
+    /// Mix all the input channels down to mono, with a gain for each
+    pub fn downmix(gains: Vec<f32>) -> ChannelMatrix {
+        ChannelMatrix::new(ChannelCount::new(gains.len() as u16), vec![gains])
+    }
+
+    /// Copy a mono input to each of the output channels
+    pub fn duplicate(outputs: ChannelCount) -> ChannelMatrix {
+        ChannelMatrix::new(ChannelCount::new(1), vec![vec![1.]; usize::from(outputs)])
+    }
+
+    pub fn inputs(&self) -> ChannelCount {
+        self.inputs
+    }
+
+    pub fn outputs(&self) -> ChannelCount {
+        self.outputs
+    }
+}
+
+impl Step for ChannelMatrix {
+    type Input = Frame;
+    type Output = Frame;
+
+    fn push_input(&mut self, frame: Frame) {
+        assert!(self.next.is_none());
+        assert!(frame.channels == self.inputs);
+        let mut samples = Vec::with_capacity(
+            frame.samples.len() / usize::from(self.inputs) * usize::from(self.outputs),
+        );
+        for input in frame.samples.chunks(usize::from(self.inputs)) {
+            for row in &self.matrix {
+                samples.push(row.iter().zip(input).map(|(g, s)| g * s).sum());
+            }
+        }
+        self.next = Some(Frame {
+            channels: self.outputs,
+            sample_rate: frame.sample_rate,
+            start_index: frame.start_index,
+            samples,
+        });
+    }
+
+    fn pop_output(&mut self) -> Option<Frame> {
+        self.next.take()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::stream::SampleRate;
+
+    fn route(step: &mut ChannelMatrix, channels: u16, samples: Vec<f32>) -> Frame {
+        step.push_input(Frame {
+            channels: ChannelCount::new(channels),
+            sample_rate: SampleRate::new(44100),
+            start_index: 10,
+            samples,
+        });
+        step.pop_output().unwrap()
+    }
+
+    #[test]
+    fn select_channels() {
+        let mut step = ChannelMatrix::select(ChannelCount::new(3), &[2, 0]);
+        let out = route(&mut step, 3, vec![1., 2., 3., 4., 5., 6.]);
+        assert_eq!(out.channels, ChannelCount::new(2));
+        assert_eq!(out.start_index, 10);
+        assert_eq!(out.samples, [3., 1., 6., 4.]);
+    }
+
+    #[test]
+    fn downmix_and_duplicate() {
+        let mut down = ChannelMatrix::downmix(vec![0.5, 0.25]);
+        let out = route(&mut down, 2, vec![1., 2., 4., 4.]);
+        assert_eq!(out.channels, ChannelCount::new(1));
+        assert_eq!(out.samples, [1., 3.]);
+
+        let mut dup = ChannelMatrix::duplicate(ChannelCount::new(3));
+        let out = route(&mut dup, 1, vec![1., 2.]);
+        assert_eq!(out.channels, ChannelCount::new(3));
+        assert_eq!(out.samples, [1., 1., 1., 2., 2., 2.]);
+    }
+}
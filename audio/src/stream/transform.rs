@@ -1,6 +1,9 @@
-use crate::dsp::fft::{FFTSequence, FoldedFFT};
+use std::iter::zip;
+
+use crate::dsp::fft::{FFTSequence, FoldedFFT, Window};
+use crate::dsp::psd::{Welch, PSD};
 use crate::stream::buffer::Period;
-use crate::stream::SampleRate;
+use crate::stream::{self, SampleRate};
 use crate::Instant;
 
 #[derive(Clone, Debug)]
@@ -24,8 +27,21 @@ impl FFT {
         }
     }
 
-    pub fn transform(&self, period: &Period) -> FFTResult {
-        assert!(self.width == period.len());
+    /// With each period zero-padded to `padded_width` (see
+    /// `FFTSequence::padded`)
+    pub fn padded(width: usize, padded_width: usize) -> FFT {
+        FFT {
+            width,
+            fft: FFTSequence::padded(width, padded_width),
+        }
+    }
+
+    pub fn with_window(mut self, window: Window) -> FFT {
+        self.fft = self.fft.with_window(window);
+        self
+    }
+
+    pub fn transform(&mut self, period: &Period) -> FFTResult {
         let mut res = FFTResult {
             end_time: period.end_time(),
             width: self.width,
@@ -33,8 +49,94 @@ impl FFT {
             ffts: Vec::new(),
         };
         for ch in period.channels() {
-            res.ffts.push(self.fft.fft(&ch).into_polar().into_folded())
+            res.ffts.push(self.fft.folded_fft(&ch))
         }
         res
     }
 }
+
+#[derive(Clone, Debug)]
+pub struct PSDResult {
+    /// The start of the first period and the end of the last period that
+    /// were averaged
+    pub start_time: Instant,
+    pub end_time: Instant,
+    pub psds: Vec<PSD>,
+}
+
+/// Welch PSDs of each channel of a stream of periods (e.g. from a
+/// `PeriodBuffer`, whose hop determines how much they overlap), each
+/// averaging a number of consecutive periods
+pub struct AveragePSD {
+    width: usize,
+    window: Window,
+    periods: usize,
+    /// (created for the first period, which determines the channel count)
+    welch: Vec<Welch>,
+    start_time: Option<Instant>,
+    /// (periods that start before this overlap the last dropout)
+    resume_time: Option<Instant>,
+}
+
+impl AveragePSD {
+    pub fn new(width: usize, window: Window, periods: usize) -> AveragePSD {
+        assert!(periods > 0);
+        AveragePSD {
+            width,
+            window,
+            periods,
+            welch: Vec::new(),
+            start_time: None,
+            resume_time: None,
+        }
+    }
+
+    /// Discard the periods that have been added, since some of the input
+    /// after them was missing (see `PeriodBuffer::push`), and skip those that
+    /// overlap the missing input, rather than averaging its silence
+    pub fn dropout(&mut self, gap: stream::Period) {
+        for welch in &mut self.welch {
+            welch.reset();
+        }
+        self.start_time = None;
+        self.resume_time = Some(gap.end());
+    }
+
+    /// Add the next period, returning the PSDs once enough periods have been
+    /// added
+    pub fn push(&mut self, period: &Period) -> Option<PSDResult> {
+        let channels = period.channels();
+        if channels.is_empty() || self.resume_time.is_some_and(|t| period.start_time() < t) {
+            return None;
+        }
+        self.resume_time = None;
+        if self.welch.len() != channels.len() {
+            self.welch = (0..channels.len())
+                .map(|_| Welch::new(self.width, self.width, self.window))
+                .collect();
+        }
+        let start_time = *self.start_time.get_or_insert(period.start_time());
+        for (welch, channel) in zip(&mut self.welch, &channels) {
+            welch.push_segment(channel);
+        }
+        if self.welch[0].segments() < self.periods {
+            return None;
+        }
+
+        self.start_time = None;
+        let psds = self
+            .welch
+            .iter_mut()
+            .map(|welch| {
+                let psd = welch.psd().unwrap();
+                welch.reset();
+                psd
+            })
+            .collect();
+        Some(PSDResult {
+            start_time,
+            end_time: period.end_time(),
+            psds,
+        })
+    }
+}
@@ -1,17 +1,18 @@
-use std::fs::File;
-use std::io::BufWriter;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use hound; // (provides .wav encoding)
 pub use hound::Result;
 
-use super::input::{ChannelCount, Frame, SampleRate};
+use super::input::{ChannelCount, Frame, Input, InputError, SampleRate};
+use super::output::{Output, OutputError};
 
-/// Used to write all the samples received from an audio input to a file
-/// (currently always ./session.wav), for ad-hoc testing and debugging.
-/// This is meant to consume from InputStream::frames, and WavWriter::frames
-/// is intended to be consumed by the application (or some additional
-/// processing step).
+/// Used to write all the samples received from an audio input to a file,
+/// for ad-hoc testing and debugging.
 pub struct WavWriter {
+    path: PathBuf,
     spec: hound::WavSpec,
     writer: hound::WavWriter<BufWriter<File>>,
     unflushed_count: usize,
@@ -19,22 +20,70 @@ pub struct WavWriter {
 }
 
 impl WavWriter {
-    pub fn new(channels: ChannelCount, sample_rate: SampleRate) -> WavWriter {
+    /// Create (or overwrite) the file at the given path
+    pub fn new<P: AsRef<Path>>(
+        path: P,
+        channels: ChannelCount,
+        sample_rate: SampleRate,
+    ) -> Result<WavWriter> {
+        let file = File::create(path.as_ref())?;
+        WavWriter::from_file(path.as_ref().to_path_buf(), file, channels, sample_rate)
+    }
+
+    fn from_file(
+        path: PathBuf,
+        file: File,
+        channels: ChannelCount,
+        sample_rate: SampleRate,
+    ) -> Result<WavWriter> {
         let spec = hound::WavSpec {
             channels: u16::from(channels),
             sample_rate: u32::from(sample_rate),
             bits_per_sample: 32,
             sample_format: hound::SampleFormat::Float,
         };
-        // TODO: parameterize the output file, have an option to not write the
-        // file, etc.
-        let writer = hound::WavWriter::create("session.wav", spec).unwrap();
-        WavWriter {
+        let writer = hound::WavWriter::new(BufWriter::new(file), spec)?;
+        Ok(WavWriter {
+            path,
             spec,
             writer,
             unflushed_count: 0,
             flush_every: usize::from(sample_rate), // i.e. every 1 second
+        })
+    }
+
+    /// Create a new file in the given directory, named for the current (UTC)
+    /// time, e.g. session-20240131-235959.wav. Existing files are never
+    /// overwritten: if there is already a recording from the same second, a
+    /// suffix is added, e.g. session-20240131-235959-2.wav
+    pub fn timestamped<P: AsRef<Path>>(
+        dir: P,
+        channels: ChannelCount,
+        sample_rate: SampleRate,
+    ) -> Result<WavWriter> {
+        let name = timestamped_name(SystemTime::now());
+        for n in 1.. {
+            let path = dir.as_ref().join(match n {
+                1 => format!("{}.wav", name),
+                n => format!("{}-{}.wav", name, n),
+            });
+            match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(file) => return WavWriter::from_file(path, file, channels, sample_rate),
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e.into()),
+            }
         }
+        unreachable!()
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Flush any buffered samples and update the .wav header. (This also
+    /// happens if the WavWriter is dropped, but errors are then ignored.)
+    pub fn finalize(self) -> Result<()> {
+        self.writer.finalize()
     }
 
     pub fn push(&mut self, frame: &Frame) -> Result<()> {
@@ -57,3 +106,237 @@ impl WavWriter {
         Ok(())
     }
 }
+
+impl Output for WavWriter {
+    fn push(&mut self, frame: Frame) -> std::result::Result<(), OutputError> {
+        WavWriter::push(self, &frame).map_err(OutputError::WavError)
+    }
+
+    fn flush(&mut self) -> std::result::Result<(), OutputError> {
+        self.writer.flush().map_err(OutputError::WavError)
+    }
+}
+
+/// The name of a session recording started at the given time (without the
+/// .wav extension)
+fn timestamped_name(time: SystemTime) -> String {
+    let secs = time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    let (days, secs_of_day) = (secs / 86400, secs % 86400);
+
+    // Convert days since the epoch to a (proleptic Gregorian) calendar date,
+    // see http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + 719468;
+    let era = z / 146097;
+    let doe = z % 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+
+    format!(
+        "session-{:04}{:02}{:02}-{:02}{:02}{:02}",
+        year,
+        month,
+        day,
+        secs_of_day / 3600,
+        secs_of_day / 60 % 60,
+        secs_of_day % 60
+    )
+}
+
+/// Reads samples from a .wav file (e.g. a session.wav written by `WavWriter`)
+/// as a sequence of `Frame`s, so that recordings can be analyzed offline in
+/// the same way as a live `InputDevice`.
+pub struct WavReader {
+    channels: ChannelCount,
+    sample_rate: SampleRate,
+    reader: hound::WavReader<BufReader<File>>,
+    /// The number of samples (per channel) in each Frame that is read
+    frame_len: usize,
+    next_index: usize,
+}
+
+impl WavReader {
+    /// The default number of samples (per channel) in each Frame. This is
+    /// similar to a typical device buffer, but it doesn't much matter.
+    pub const DEFAULT_FRAME_LEN: usize = 1024;
+
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<WavReader> {
+        let reader = hound::WavReader::open(path)?;
+        let spec = reader.spec();
+        Ok(WavReader {
+            channels: ChannelCount::new(spec.channels),
+            sample_rate: SampleRate::new(spec.sample_rate),
+            reader,
+            frame_len: WavReader::DEFAULT_FRAME_LEN,
+            next_index: 0,
+        })
+    }
+
+    pub fn with_frame_len(mut self, frame_len: usize) -> Self {
+        assert!(frame_len > 0);
+        self.frame_len = frame_len;
+        self
+    }
+
+    pub fn channels(&self) -> ChannelCount {
+        self.channels
+    }
+
+    pub fn sample_rate(&self) -> SampleRate {
+        self.sample_rate
+    }
+
+    /// The number of samples (per channel) that have not been read yet
+    pub fn remaining(&self) -> usize {
+        // (hound's len() is the total, regardless of what's been read)
+        self.reader.duration() as usize - self.next_index
+    }
+
+    fn read_samples(&mut self) -> Result<Vec<f32>> {
+        let max_len = self.frame_len * usize::from(self.channels);
+        let spec = self.reader.spec();
+        match spec.sample_format {
+            hound::SampleFormat::Float => self.reader.samples::<f32>().take(max_len).collect(),
+            hound::SampleFormat::Int => {
+                // Scale integer samples to [-1.0, 1.0), i.e. full scale:
+                let scale = 1.0 / (1u64 << (spec.bits_per_sample - 1)) as f32;
+                self.reader
+                    .samples::<i32>()
+                    .take(max_len)
+                    .map(|s| s.map(|s| s as f32 * scale))
+                    .collect()
+            }
+        }
+    }
+}
+
+impl Input for WavReader {
+    type Item = Frame;
+
+    fn read(&mut self) -> std::result::Result<Frame, InputError> {
+        let samples = self.read_samples().map_err(InputError::WavError)?;
+        if samples.is_empty() {
+            Err(InputError::StreamEnded)
+        } else {
+            let frame = Frame {
+                channels: self.channels,
+                sample_rate: self.sample_rate,
+                start_index: self.next_index,
+                samples,
+            };
+            self.next_index = frame.end_index();
+            Ok(frame)
+        }
+    }
+
+    fn try_read(&mut self) -> std::result::Result<Option<Frame>, InputError> {
+        // The file is always ready to be read
+        self.read().map(Some)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn timestamped_names() {
+        assert_eq!(timestamped_name(UNIX_EPOCH), "session-19700101-000000");
+        assert_eq!(
+            timestamped_name(UNIX_EPOCH + Duration::from_secs(1709251199)),
+            "session-20240229-235959"
+        );
+    }
+
+    #[test]
+    fn timestamped_in_same_second() {
+        let dir = env::temp_dir().join("arcavox_timestamped_in_same_second");
+        std::fs::create_dir_all(&dir).unwrap();
+        let (channels, sample_rate) = (ChannelCount::new(1), SampleRate::new(100));
+        // (three, so that at least two are in the same second)
+        let writers: Vec<WavWriter> = (0..3)
+            .map(|_| WavWriter::timestamped(&dir, channels, sample_rate).unwrap())
+            .collect();
+        let mut paths: Vec<&Path> = writers.iter().map(|w| w.path()).collect();
+        paths.sort();
+        paths.dedup();
+        assert_eq!(paths.len(), 3);
+        assert!(paths
+            .iter()
+            .any(|p| p.to_str().unwrap().ends_with("-2.wav")));
+        drop(writers);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn write_read_roundtrip() {
+        let channels = ChannelCount::new(1);
+        let sample_rate = SampleRate::new(100);
+        let path = env::temp_dir().join("arcavox_write_read_roundtrip.wav");
+        let mut writer = WavWriter::new(&path, channels, sample_rate).unwrap();
+        writer
+            .push(&Frame {
+                channels,
+                sample_rate,
+                start_index: 0,
+                samples: vec![0.25, -0.5, 1.],
+            })
+            .unwrap();
+        writer.finalize().unwrap();
+
+        let mut reader = WavReader::open(&path).unwrap();
+        assert_eq!(reader.read().unwrap().samples, [0.25, -0.5, 1.]);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    fn write_test_file(name: &str, spec: hound::WavSpec, samples: &[i32]) -> PathBuf {
+        let path = env::temp_dir().join(name);
+        let mut writer = hound::WavWriter::create(&path, spec).unwrap();
+        for s in samples {
+            writer.write_sample(*s).unwrap();
+        }
+        writer.finalize().unwrap();
+        path
+    }
+
+    #[test]
+    fn read_int_frames() {
+        let spec = hound::WavSpec {
+            channels: 2,
+            sample_rate: 8000,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let path = write_test_file(
+            "arcavox_read_int_frames.wav",
+            spec,
+            &[0, 16384, -16384, -32768, 8192, 0],
+        );
+
+        let mut reader = WavReader::open(&path).unwrap().with_frame_len(2);
+        assert_eq!(reader.channels(), ChannelCount::new(2));
+        assert_eq!(reader.sample_rate(), SampleRate::new(8000));
+        assert_eq!(reader.remaining(), 3);
+
+        let f = reader.read().unwrap();
+        assert_eq!(f.channels, ChannelCount::new(2));
+        assert_eq!(f.sample_rate, SampleRate::new(8000));
+        assert_eq!(f.samples, [0., 0.5, -0.5, -1.]);
+        assert_eq!(reader.remaining(), 1);
+
+        // The last frame is short, because the file ends:
+        let f = reader.read().unwrap();
+        assert_eq!(f.start_index, 2);
+        assert_eq!(f.samples, [0.25, 0.]);
+        assert_eq!(reader.remaining(), 0);
+
+        assert!(matches!(reader.read(), Err(InputError::StreamEnded)));
+        std::fs::remove_file(path).unwrap();
+    }
+}
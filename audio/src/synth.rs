@@ -1,8 +1,11 @@
+use std::collections::VecDeque;
 use std::f32::consts::PI;
+use std::f64::consts::TAU;
 
 use crate::dsp::Decibels;
 use crate::stream::input::SampleRate;
 use crate::stream::pipeline::Step;
+use crate::stream::{Duration, Instant};
 
 /// An iterator that returns and infinite sequence of sample times (seconds)
 /// for a given sample rate (which is a useful base for synthesizing signals)
@@ -30,25 +33,195 @@ impl Iterator for SampleClock {
     }
 }
 
+/// A change to a `Param`, which can be scheduled for a particular sample,
+/// and ramp to the new value
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Change<T = f32> {
+    pub value: T,
+    /// The sample at which to start changing, or None for the next sample
+    pub at: Option<Instant>,
+    /// How long to take to reach the value (linearly), or None to jump to it
+    pub ramp: Option<Duration>,
+}
+
+impl<T> Change<T> {
+    /// Change to the given value at the next sample
+    pub fn new(value: T) -> Change<T> {
+        Change {
+            value,
+            at: None,
+            ramp: None,
+        }
+    }
+
+    pub fn at(mut self, at: Instant) -> Self {
+        self.at = Some(at);
+        self
+    }
+
+    pub fn with_ramp(mut self, ramp: Duration) -> Self {
+        self.ramp = Some(ramp);
+        self
+    }
+
+    fn map<U>(self, f: impl FnOnce(T) -> U) -> Change<U> {
+        Change {
+            value: f(self.value),
+            at: self.at,
+            ramp: self.ramp,
+        }
+    }
+}
+
+/// A parameter (e.g. of a synthesizer or a `Step`) that has a value for each
+/// sample, so that changes to it are sample-accurate, and can be ramped so
+/// that they aren't audible as a step.
+/// Samples are indexed from when the `Param` was created, i.e. the index of
+/// the sample returned by the first call to `next()` is 0, unless the index
+/// is set (e.g. to the start of each `Frame` that it's applied to, so that
+/// changes are scheduled in the same time as the frames).
+pub struct Param {
+    value: f32,
+    /// (None until it's given, or taken from the first change that's
+    /// scheduled at an `Instant`)
+    sample_rate: Option<SampleRate>,
+    /// The index of the next sample
+    index: usize,
+    /// (the increment per sample, and the number of samples remaining, of a
+    /// change that is in progress)
+    ramp: Option<(f32, usize)>,
+    target: f32,
+    /// Changes that haven't started yet, in the order that they start, with
+    /// the index of the sample at which they start
+    scheduled: VecDeque<(usize, Change)>,
+}
+
+impl Param {
+    pub fn new(value: f32, sample_rate: SampleRate) -> Param {
+        Param {
+            sample_rate: Some(sample_rate),
+            ..Param::from_value(value)
+        }
+    }
+
+    /// A `Param` without a sample rate, which takes it from the first change
+    /// that's scheduled at an `Instant`
+    pub fn from_value(value: f32) -> Param {
+        Param {
+            value,
+            sample_rate: None,
+            index: 0,
+            ramp: None,
+            target: value,
+            scheduled: VecDeque::new(),
+        }
+    }
+
+    /// The value for the next sample
+    pub fn value(&self) -> f32 {
+        self.value
+    }
+
+    /// The value after all the changes that are scheduled or in progress
+    pub fn target(&self) -> f32 {
+        self.scheduled.back().map_or(self.target, |(_, c)| c.value)
+    }
+
+    /// Schedule a change, after any others that start at the same sample.
+    /// A change that is scheduled for a sample that has already passed starts
+    /// at the next sample. A change that starts during a ramp ends that ramp,
+    /// and starts from its current value.
+    ///
+    /// # Panics
+    /// If the change is `at` an `Instant` with a different sample rate to the
+    /// `Param`'s
+    pub fn schedule(&mut self, change: Change) {
+        let start = change.at.map_or(self.index, |at| {
+            let sample_rate = *self.sample_rate.get_or_insert(at.sample_rate());
+            at.index(sample_rate).max(self.index)
+        });
+        let i = self.scheduled.partition_point(|(s, _)| *s <= start);
+        self.scheduled.insert(i, (start, change));
+    }
+
+    /// Change to the value immediately (i.e. from the next sample)
+    pub fn set(&mut self, value: f32) {
+        self.schedule(Change::new(value));
+    }
+
+    /// Set the index of the next sample (e.g. the start index of the next
+    /// `Frame`), which scheduled changes start relative to
+    pub fn set_index(&mut self, index: usize) {
+        self.index = index;
+    }
+
+    fn start(&mut self, change: Change) {
+        let len = change.ramp.map_or(0, |r| r.sample_count());
+        self.target = change.value;
+        if len == 0 {
+            self.value = change.value;
+            self.ramp = None;
+        } else {
+            self.ramp = Some(((change.value - self.value) / len as f32, len));
+        }
+    }
+}
+
+impl Iterator for Param {
+    type Item = f32;
+
+    /// The value for the next sample (which is infinite)
+    fn next(&mut self) -> Option<f32> {
+        while let Some(&(start, change)) = self.scheduled.front() {
+            if start > self.index {
+                break;
+            }
+            self.scheduled.pop_front();
+            self.start(change);
+        }
+        let value = self.value;
+        if let Some((step, remaining)) = &mut self.ramp {
+            *remaining -= 1;
+            if *remaining == 0 {
+                // (exactly, regardless of rounding errors)
+                self.value = self.target;
+                self.ramp = None;
+            } else {
+                self.value += *step;
+            }
+        }
+        self.index += 1;
+        Some(value)
+    }
+}
+
 /// An Iterator that produces an infinite sinusoid
 pub struct SinIterator {
-    frequency: f32,
-    phase: f32,
-    clock: SampleClock,
+    frequency: Param,
+    /// (in radians, which is accumulated so that changes in frequency are
+    /// continuous)
+    phase: f64,
+    sample_rate: f64,
 }
 
 impl SinIterator {
     /// frequency is in Hz, phase is in radians
     pub fn new(sample_rate: SampleRate, frequency: f32, phase: f32) -> SinIterator {
         SinIterator {
-            frequency,
-            phase,
-            clock: SampleClock::new(sample_rate),
+            frequency: Param::new(frequency, sample_rate),
+            phase: f64::from(phase),
+            sample_rate: f64::from(u32::from(sample_rate)),
         }
     }
 
     pub fn set_frequency(&mut self, frequency: f32) {
-        self.frequency = frequency
+        self.frequency.set(frequency)
+    }
+
+    /// Change the frequency at a given sample (as indexed from the first
+    /// sample produced), and/or gradually
+    pub fn schedule_frequency(&mut self, change: Change) {
+        self.frequency.schedule(change)
     }
 }
 
@@ -56,10 +229,10 @@ impl Iterator for SinIterator {
     type Item = f32;
 
     fn next(&mut self) -> Option<f32> {
-        match self.clock.next() {
-            Some(t) => Some((2. * PI * self.frequency * t + self.phase).sin()),
-            None => panic!("impossible, clock is infinite"),
-        }
+        let sample = self.phase.sin() as f32;
+        let frequency = f64::from(self.frequency.next().unwrap()); // (infinite)
+        self.phase = (self.phase + TAU * frequency / self.sample_rate) % TAU;
+        Some(sample)
     }
 }
 
@@ -91,22 +264,45 @@ impl Iterator for ChirpIterator {
     }
 }
 
+/// Converts from a power ratio to an amplitude ratio
+fn amplitude(gain: Decibels) -> f32 {
+    gain.into_full_scale().sqrt()
+}
+
 pub struct Gain {
-    gain: f32,
+    /// (as an amplitude ratio, so that ramps are linear in amplitude)
+    gain: Param,
     next: Option<f32>,
 }
 
 impl Gain {
     pub fn new(gain: Decibels) -> Gain {
         Gain {
-            // sqrt converts from power ratio to amplitude ratio
-            gain: gain.into_full_scale().sqrt(),
+            gain: Param::from_value(amplitude(gain)),
+            next: None,
+        }
+    }
+
+    /// A gain that changes can only be scheduled for at an `Instant` of a
+    /// stream with the given sample rate (whereas `new` takes the rate from
+    /// the first such change)
+    pub fn with_sample_rate(gain: Decibels, sample_rate: SampleRate) -> Gain {
+        Gain {
+            gain: Param::new(amplitude(gain), sample_rate),
             next: None,
         }
     }
 
     pub fn set_gain(&mut self, gain: Decibels) {
-        self.gain = gain.into_full_scale().sqrt();
+        self.gain.set(amplitude(gain));
+    }
+
+    /// Change the gain at a given sample (as indexed by the frames, when it's
+    /// applied to them by a `PerChannel`, or from the first sample input,
+    /// otherwise), and/or gradually.
+    /// (see `Param::schedule`)
+    pub fn schedule(&mut self, change: Change<Decibels>) {
+        self.gain.schedule(change.map(amplitude));
     }
 }
 
@@ -122,17 +318,29 @@ impl Step for Gain {
 
     fn push_input(&mut self, v: f32) {
         assert!(self.next.is_none());
-        self.next = Some(v * self.gain);
+        self.next = Some(v * self.gain.next().unwrap());
     }
 
     fn pop_output(&mut self) -> Option<f32> {
         self.next.take()
     }
+
+    fn seek(&mut self, index: usize) {
+        self.gain.set_index(index);
+    }
+
+    fn process_block<It: IntoIterator<Item = f32>>(&mut self, input: It, output: &mut Vec<f32>) {
+        assert!(self.next.is_none());
+        let gain = &mut self.gain;
+        output.extend(input.into_iter().map(|v| v * gain.next().unwrap()));
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::stream::pipeline::PerChannel;
+    use crate::stream::{ChannelCount, Frame};
 
     fn assert_samples_eq(left: &Vec<f32>, right: &Vec<f32>) {
         let eq = if left.len() == right.len() {
@@ -166,4 +374,85 @@ mod tests {
         let inv_sqrt_2 = 1.0 / 2f32.sqrt();
         assert_samples_eq(&samples, &vec![1., inv_sqrt_2, 0., -inv_sqrt_2])
     }
+
+    #[test]
+    fn scheduled_changes() {
+        let rate = SampleRate::new(10);
+        let mut param = Param::new(1., rate);
+        param.schedule(Change::new(2.).at(Instant::new(5, rate)));
+        param.schedule(
+            Change::new(0.)
+                .at(Instant::new(2, rate))
+                .with_ramp(Duration::new(2, rate)),
+        );
+        assert_eq!(param.target(), 2.);
+        let values: Vec<f32> = param.by_ref().take(6).collect();
+        assert_eq!(values, [1., 1., 1., 0.5, 0., 2.]);
+
+        // (a change that is late starts at the next sample, and one during a
+        // ramp starts from the current value)
+        param.schedule(
+            Change::new(4.)
+                .at(Instant::new(1, rate))
+                .with_ramp(Duration::new(4, rate)),
+        );
+        param.schedule(
+            Change::new(0.)
+                .at(Instant::new(8, rate))
+                .with_ramp(Duration::new(2, rate)),
+        );
+        let values: Vec<f32> = param.take(6).collect();
+        assert_eq!(values, [2., 2.5, 3., 1.5, 0., 0.]);
+    }
+
+    #[test]
+    fn continuous_frequency_change() {
+        let rate = SampleRate::new(1000);
+        let mut sin = SinIterator::new(rate, 10., 0.);
+        sin.schedule_frequency(Change::new(20.).at(Instant::new(25, rate)));
+        let samples: Vec<f32> = sin.take(50).collect();
+        // A quarter period at 10 Hz, then 20 Hz with no discontinuity:
+        assert_abs_diff_eq!(samples[25], 1., epsilon = 1e-6);
+        assert_abs_diff_eq!(samples[37], 0.0, epsilon = 0.07);
+        for pair in samples.windows(2) {
+            assert!((pair[1] - pair[0]).abs() < 0.13);
+        }
+    }
+
+    #[test]
+    fn gain_scheduled_by_frame() {
+        let rate = SampleRate::new(10);
+        let channels = ChannelCount::new(2);
+        let mut gain =
+            PerChannel::new(channels, || Gain::with_sample_rate(Decibels::new(0.), rate));
+        for g in gain.steps_mut() {
+            g.schedule(Change::new(Decibels::new(-20.)).at(Instant::new(10, rate)));
+        }
+        // The first frame starts after a gap, so the change is part of the
+        // way through it (rather than after 10 samples have been processed):
+        let mut output = Vec::new();
+        gain.process_block(
+            [Frame {
+                channels,
+                sample_rate: rate,
+                start_index: 8,
+                samples: vec![1.; 8],
+            }],
+            &mut output,
+        );
+        assert_samples_eq(
+            &output[0].samples,
+            &vec![1., 1., 1., 1., 0.1, 0.1, 0.1, 0.1],
+        );
+    }
+
+    #[test]
+    fn sample_rate_from_change() {
+        let rate = SampleRate::new(10);
+        let mut gain = Gain::new(Decibels::new(0.));
+        gain.schedule(Change::new(Decibels::new(-20.)).at(Instant::new(2, rate)));
+        let mut output = Vec::new();
+        gain.process_block([1.; 4], &mut output);
+        assert_samples_eq(&output, &vec![1., 1., 0.1, 0.1]);
+    }
 }
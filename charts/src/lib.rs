@@ -1,4 +1,5 @@
 use audio::dsp::fft::FoldedFFT;
+use audio::dsp::psd::PSD;
 use plotters::prelude::*;
 use std::f32::consts::PI;
 
@@ -57,3 +58,43 @@ pub fn build_fft_chart<DB: DrawingBackend>(
 
     Ok(())
 }
+
+pub fn build_psd_chart<DB: DrawingBackend>(
+    mut builder: ChartBuilder<DB>,
+    psd: &PSD,
+) -> Result<(), DrawingAreaErrorKind<DB::ErrorType>> {
+    let min_db = -160f32;
+    let mut chart = builder
+        .margin(20)
+        .x_label_area_size(40)
+        .y_label_area_size(40)
+        .build_cartesian_2d(0f32..f32::from(psd.nyquist_frequency()), min_db..0f32)?;
+
+    chart
+        .configure_mesh()
+        .x_max_light_lines(0)
+        .y_max_light_lines(0)
+        .y_desc("Power density (dB FS/Hz)")
+        .x_desc("Frequency (Hz)")
+        .draw()?;
+
+    let densities = psd
+        .frequencies()
+        .zip(psd.values.iter())
+        // (silent bins would be -infinity dB)
+        .map(|(f, p)| (f32::from(f), (10. * p.log10()).max(min_db)));
+    chart
+        .draw_series(LineSeries::new(densities, &BLUE))
+        .unwrap()
+        .label(format!("Average of {} periods", psd.segments))
+        .legend(|(x, y)| PathElement::new(vec![(x, y), (x + 20, y)], BLUE));
+
+    chart
+        .configure_series_labels()
+        .position(SeriesLabelPosition::UpperRight)
+        .background_style(WHITE.mix(0.8))
+        .border_style(BLACK)
+        .draw()?;
+
+    Ok(())
+}
@@ -1,44 +1,117 @@
-use async_channel::Sender;
-use iced::{widget, Element, Length, Padding};
+use async_channel::{Receiver, Sender};
+use clap::Parser;
+use iced::futures::StreamExt;
+use iced::{widget, Element, Length, Padding, Subscription};
 
 use audio::dsp::Decibels;
 use audio::stream::buffer::FrameAccumulator;
-use audio::stream::executor;
+use audio::stream::device::{self, DeviceId, DeviceSelector};
+use audio::stream::executor::{Control, Health, PipelineExecutor, Status};
 use audio::stream::output::OutputDevice;
-use audio::stream::pipeline::{Chain, Pipeline};
-use audio::stream::{ChannelCount, SampleRate};
-use audio::synth::{Gain, SinIterator};
+use audio::stream::pipeline::{Chain, PerChannel, Pipeline, ProcessError};
+use audio::stream::routing::ChannelMatrix;
+use audio::stream::{ChannelCount, Duration, SampleRate};
+use audio::synth::{Change, Gain, SinIterator};
+
+#[derive(Debug, Parser)]
+struct Args {
+    /// The audio host (API) to use, instead of the platform default
+    #[arg(long)]
+    host: Option<String>,
+    /// The output device to use (by name or index), instead of the default
+    #[arg(long)]
+    output_device: Option<DeviceId>,
+    /// The number of output channels (the same sound is played on each)
+    #[arg(long, default_value_t = 1)]
+    channels: u16,
+    /// List the available audio hosts and devices, and exit
+    #[arg(long)]
+    list_devices: bool,
+}
+
+const SAMPLE_RATE: u32 = 44100;
+
+/// How long changes from the UI take to ramp to their new values (so that the
+/// steps of a slider aren't audible as clicks)
+const RAMP_SAMPLES: usize = SAMPLE_RATE as usize / 50;
 
 #[derive(Clone, Debug)]
 enum Message {
     FrequencyChanged(f32),
     GainChanged(f32),
+    PlayPause,
+    /// (from the executor)
+    Status(Status),
 }
 
 struct Synthesizer {
-    request_sender: Sender<Message>,
+    request_sender: Sender<Control<Message>>,
+    status: Receiver<Status>,
     gain: Decibels,
     frequency: f32,
+    paused: bool,
+    /// Whether the executor has stopped (so controls can't be sent to it)
+    stopped: bool,
+    health: Health,
+    /// The most recent error, or why playback stopped
+    problem: Option<String>,
+}
+
+#[derive(Hash)]
+enum SubscriptionId {
+    Status,
 }
 
-impl Default for Synthesizer {
-    fn default() -> Synthesizer {
-        let channels = ChannelCount::new(1);
-        let sample_rate = SampleRate::new(44100);
-        let (request_sender, _recv, _join) = executor::PipelineExecutor::start(
-            channels,
-            sample_rate,
-            SinIterator::new(sample_rate, 200., 0.),
-            Chain::new(
-                Gain::new(Decibels::new(0.)),
-                FrameAccumulator::new(channels, sample_rate, OutputDevice::DEVICE_BUFFER as usize),
-            ),
-            Box::new(update_pipeline),
+impl Synthesizer {
+    fn new(args: Args) -> Synthesizer {
+        let channels = ChannelCount::new(args.channels);
+        let sample_rate = SampleRate::new(SAMPLE_RATE);
+        let device = DeviceSelector {
+            host: args.host,
+            device: args.output_device,
+        };
+        let executor = PipelineExecutor::start(
+            move |_: Sender<()>| {
+                Ok(Pipeline::new(
+                    SinIterator::new(sample_rate, 200., 0.),
+                    Chain::new(
+                        FrameAccumulator::new(
+                            ChannelCount::new(1),
+                            sample_rate,
+                            OutputDevice::DEVICE_BUFFER as usize,
+                        ),
+                        Chain::new(
+                            ChannelMatrix::duplicate(channels),
+                            PerChannel::new(channels, || {
+                                Gain::with_sample_rate(Decibels::new(0.), sample_rate)
+                            }),
+                        ),
+                    ),
+                    // (if this fails, the executor reports it as its status)
+                    OutputDevice::open(&device, channels, sample_rate)
+                        .map_err(ProcessError::OpenError)?,
+                ))
+            },
+            update_pipeline,
         );
         Synthesizer {
-            request_sender,
+            request_sender: executor.commands,
+            status: executor.status,
             gain: Decibels::new(0.),
             frequency: 200.,
+            paused: false,
+            stopped: false,
+            health: Health::default(),
+            problem: None,
+        }
+    }
+
+    /// Send a control to the executor, unless (or until) it has stopped
+    fn send(&mut self, control: Control<Message>) {
+        // TODO: can this be async?
+        if !self.stopped && self.request_sender.send_blocking(control).is_err() {
+            self.stopped = true;
+            self.problem.get_or_insert_with(|| "Stopped".to_string());
         }
     }
 }
@@ -47,17 +120,40 @@ fn update(synth: &mut Synthesizer, message: Message) {
     match message {
         Message::GainChanged(new_gain) => {
             synth.gain = Decibels::new(new_gain);
-            // TODO: can this be async?
-            synth.request_sender.send_blocking(message).unwrap();
+            synth.send(Control::Command(message));
         }
         Message::FrequencyChanged(new_freq) => {
             synth.frequency = new_freq;
-            synth.request_sender.send_blocking(message).unwrap();
+            synth.send(Control::Command(message));
+        }
+        Message::PlayPause => {
+            synth.paused = !synth.paused;
+            let control = if synth.paused {
+                Control::Pause
+            } else {
+                Control::Play
+            };
+            synth.send(control);
+        }
+        Message::Status(Status::Health(health)) => synth.health = health,
+        Message::Status(Status::Error(e)) => synth.problem = Some(format!("Error: {:?}", e)),
+        Message::Status(Status::Paused) => synth.paused = true,
+        Message::Status(Status::Playing) => synth.paused = false,
+        Message::Status(Status::Stopped(reason)) => {
+            synth.stopped = true;
+            synth.problem = Some(format!("Stopped: {:?}", reason))
+        }
+        Message::Status(Status::Failed(e)) => {
+            synth.stopped = true;
+            synth.problem = Some(match &*e {
+                ProcessError::OpenError(e) => format!("Failed to open the output: {:?}", e),
+                e => format!("Stopped after error: {:?}", e),
+            })
         }
     }
 }
 
-fn view(synth: &Synthesizer) -> Element<Message> {
+fn view(synth: &Synthesizer) -> Element<'_, Message> {
     widget::Container::new(widget::column![
         widget::row![
             widget::text("Gain"),
@@ -72,7 +168,14 @@ fn view(synth: &Synthesizer) -> Element<Message> {
             widget::slider(50f32..=2000f32, synth.frequency, Message::FrequencyChanged),
             widget::Space::new(Length::Fixed(10.), Length::Shrink),
             widget::text(format!("{} Hz", synth.frequency))
-        ]
+        ],
+        widget::button(if synth.paused { "Play" } else { "Pause" })
+            .on_press_maybe((!synth.stopped).then_some(Message::PlayPause)),
+        widget::text(format!(
+            "Output: {} underruns, {} frames queued",
+            synth.health.underruns, synth.health.queue_len
+        )),
+        widget::text(synth.problem.as_deref().unwrap_or_default())
     ])
     .width(Length::Fill)
     .height(Length::Fill)
@@ -80,18 +183,44 @@ fn view(synth: &Synthesizer) -> Element<Message> {
     .into()
 }
 
-fn update_pipeline(
-    p: &mut Pipeline<SinIterator, Chain<Gain, FrameAccumulator>, OutputDevice>,
-    cmd: Message,
-) {
+fn subscription(synth: &Synthesizer) -> Subscription<Message> {
+    Subscription::run_with_id(
+        SubscriptionId::Status,
+        synth.status.clone().map(Message::Status),
+    )
+}
+
+/// Accumulates mono samples into frames, copies them to each output channel,
+/// then applies the gain
+type SynthStep = Chain<FrameAccumulator, Chain<ChannelMatrix, PerChannel<Gain>>>;
+
+fn update_pipeline(p: &mut Pipeline<SinIterator, SynthStep, OutputDevice>, cmd: Message) {
+    let ramp = Duration::new(RAMP_SAMPLES, SampleRate::new(SAMPLE_RATE));
     match cmd {
-        Message::GainChanged(gain) => p.step_mut().first_mut().set_gain(Decibels::new(gain)),
-        Message::FrequencyChanged(freq) => p.input_mut().set_frequency(freq),
+        Message::GainChanged(gain) => {
+            for g in p.step_mut().second_mut().second_mut().steps_mut() {
+                g.schedule(Change::new(Decibels::new(gain)).with_ramp(ramp));
+            }
+        }
+        Message::FrequencyChanged(freq) => p
+            .input_mut()
+            .schedule_frequency(Change::new(freq).with_ramp(ramp)),
+        // (handled by the UI)
+        Message::PlayPause | Message::Status(_) => (),
     }
 }
 
 fn main() -> iced::Result {
+    let args = Args::parse();
+    if args.list_devices {
+        for host in device::hosts() {
+            print!("{}", host);
+        }
+        return Ok(());
+    }
+
     iced::application("Synthesizer", update, view)
         .antialiasing(true) // see analyzer_app::main
-        .run()
+        .subscription(subscription)
+        .run_with(move || (Synthesizer::new(args), iced::Task::none()))
 }
@@ -29,18 +29,13 @@ impl Default for SpecExample {
         let sample_rate = SampleRate::new(SAMPLE_RATE);
         let mut synth = ChirpIterator::new(sample_rate, BASE_FREQ, FREQ_SLOPE);
         let mut buf = SampleBuffer::new(ChannelCount::new(1), sample_rate, WINDOW_SIZE);
-        let ffter = FFTSequence::new(WINDOW_SIZE);
+        let mut ffter = FFTSequence::new(WINDOW_SIZE);
         let mut ffts = Vec::new();
 
         for i in 0..WINDOW_COUNT {
             buf.push_some_mono(&mut synth, WINDOW_SIZE);
             let window = Period::new(i * WINDOW_SIZE, WINDOW_SIZE, sample_rate);
-            ffts.push(
-                ffter
-                    .fft(&buf.get_window(window).get_channel(0))
-                    .into_polar()
-                    .into_folded(),
-            );
+            ffts.push(ffter.folded_fft(&buf.get_window(window).get_channel(0)));
         }
 
         SpecExample { ffts }
@@ -52,7 +47,7 @@ struct Message();
 
 fn update(_ex: &mut SpecExample, _message: Message) {}
 
-fn view(ex: &SpecExample) -> Element<Message> {
+fn view(ex: &SpecExample) -> Element<'_, Message> {
     widget::Container::new(
         canvas(Spectrogram::new(ex.ffts.clone()))
             .width(Length::Fill)
@@ -80,7 +80,7 @@ impl Transform<Instant, f32> for InstantView {
     }
 
     fn inverse(&self, val: f32) -> Instant {
-        let frac = (val + 1.0) / 2.0;
+        let frac = f32::midpoint(val, 1.0);
         let delta = (frac * self.period.duration().sample_count() as f32).round() as isize;
         if delta >= 0 {
             self.period.start() + Duration::new(delta as usize, self.period.sample_rate())
@@ -117,7 +117,7 @@ impl Transform<Hz, f32> for PitchView {
 
     fn inverse(&self, val: f32) -> Hz {
         // (-1, 1) -> (0, 1)
-        let mut res = (val + 1.0) / 2.0;
+        let mut res = f32::midpoint(val, 1.0);
         // to the log-ratio:
         res = res * (self.log_ratio_range.end - self.log_ratio_range.start) + self.log_ratio_range.start;
         // invert log and multiply out ratio: